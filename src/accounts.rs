@@ -0,0 +1,173 @@
+use std::fs::{File, OpenOptions};
+use std::error::Error;
+use std::io::{Read, Write, ErrorKind};
+
+use std::sync::Mutex;
+use std::collections::HashMap;
+
+use rand::random;
+use rustc_serialize::hex::{ToHex, FromHex};
+
+use crypto::pbkdf2::pbkdf2;
+use crypto::hmac::Hmac;
+use crypto::sha2::Sha256;
+use crypto::util::fixed_time_eq;
+
+/*
+Локальные аккаунты нужны, когда главный сервер недоступен(TCPConnectionStage::LoginOrRegister).
+Файл accounts.list, каждая строка: userID userName salt hash(salt и hash в hex)
+Пароль хэшируется pbkdf2(hmac-sha256) с солью, которая у каждого аккаунта своя.
+*/
+
+const ACCOUNTS_FILE_NAME: &'static str = "accounts.list";
+
+///userID of local accounts begin with it, so they do not intersect with userID of master server
+pub const LOCAL_USER_ID_BEGIN: usize = 0x4000_0000;
+
+const SALT_LENGTH: usize = 16;
+const HASH_LENGTH: usize = 32;
+const HASH_ITERATIONS: u32 = 4096;
+
+const USER_NAME_LENGTH_MIN: usize = 3;
+const USER_NAME_LENGTH_MAX: usize = 32;
+const PASSWORD_LENGTH_MIN: usize = 6;
+const PASSWORD_LENGTH_MAX: usize = 128;
+
+struct Account{
+    userID:usize,
+    salt:Vec<u8>,
+    hash:Vec<u8>,
+}
+
+struct Accounts{
+    byName:HashMap<String, Account>,
+    nextUserID:usize,
+}
+
+pub struct AccountStore{
+    accounts:Mutex<Accounts>,
+}
+
+impl AccountStore{
+    pub fn load() -> Result<AccountStore, String> {
+        let mut accounts=Accounts{
+            byName:HashMap::new(),
+            nextUserID:LOCAL_USER_ID_BEGIN,
+        };
+
+        let mut file=match File::open(ACCOUNTS_FILE_NAME) {
+            Ok( f ) => f,
+            Err( ref e ) if e.kind()==ErrorKind::NotFound => return Ok( AccountStore{ accounts:Mutex::new(accounts) } ),
+            Err( e ) => return Err(format!("Can not read file \"{}\" : {}", ACCOUNTS_FILE_NAME, e.description())),
+        };
+
+        let mut content = String::new();
+        match file.read_to_string(&mut content){
+            Ok( _ )  => {},
+            Err( e ) => return Err(format!("Can not read file \"{}\" : {}", ACCOUNTS_FILE_NAME, e.description())),
+        }
+
+        for (lineNumber, line) in content.lines().enumerate() {
+            if line.trim().len()==0 {
+                continue;
+            }
+
+            let parts:Vec<&str>=line.split_whitespace().collect();
+
+            if parts.len()!=4 {
+                return Err(format!("File \"{}\" line {} : expected userID, userName, salt and hash", ACCOUNTS_FILE_NAME, lineNumber+1));
+            }
+
+            let userID=try!(parts[0].parse::<usize>().or( Err(format!("File \"{}\" line {} : invalid userID", ACCOUNTS_FILE_NAME, lineNumber+1)) ));
+            let salt=try!(parts[2].from_hex().or( Err(format!("File \"{}\" line {} : invalid salt", ACCOUNTS_FILE_NAME, lineNumber+1)) ));
+            let hash=try!(parts[3].from_hex().or( Err(format!("File \"{}\" line {} : invalid hash", ACCOUNTS_FILE_NAME, lineNumber+1)) ));
+
+            if userID>=accounts.nextUserID {
+                accounts.nextUserID=userID+1;
+            }
+
+            accounts.byName.insert(String::from(parts[1]), Account{ userID:userID, salt:salt, hash:hash });
+        }
+
+        Ok( AccountStore{ accounts:Mutex::new(accounts) } )
+    }
+
+    ///Returns userID
+    pub fn register(&self, userName:&str, password:&str) -> Result<usize, String> {
+        try!(checkUserName(userName));
+        try!(checkPassword(password));
+
+        let mut accounts=self.accounts.lock().unwrap();
+
+        if accounts.byName.contains_key(userName) {
+            return Err( format!("User \"{}\" already exists", userName) );
+        }
+
+        let mut salt=Vec::with_capacity(SALT_LENGTH);
+
+        for _ in 0..SALT_LENGTH {
+            salt.push(random::<u8>());
+        }
+
+        let hash=hashPassword(password, &salt);
+        let userID=accounts.nextUserID;
+
+        let line=format!("{} {} {} {}\n", userID, userName, salt.to_hex(), hash.to_hex());
+
+        let mut file=try!(OpenOptions::new().create(true).append(true).open(ACCOUNTS_FILE_NAME)
+            .or_else(|e| Err(format!("Can not write file \"{}\" : {}", ACCOUNTS_FILE_NAME, e.description()))));
+
+        try!(file.write_all(line.as_bytes())
+            .or_else(|e| Err(format!("Can not write file \"{}\" : {}", ACCOUNTS_FILE_NAME, e.description()))));
+
+        accounts.nextUserID+=1;
+        accounts.byName.insert(String::from(userName), Account{ userID:userID, salt:salt, hash:hash });
+
+        Ok(userID)
+    }
+
+    ///Returns userID
+    pub fn login(&self, userName:&str, password:&str) -> Result<usize, String> {
+        let accounts=self.accounts.lock().unwrap();
+
+        match accounts.byName.get(userName) {
+            Some( account ) => {
+                if fixed_time_eq(&hashPassword(password, &account.salt)[..], &account.hash[..]) {
+                    Ok(account.userID)
+                }else{
+                    Err( String::from("Invalid user name or password") )
+                }
+            },
+            None => Err( String::from("Invalid user name or password") ),
+        }
+    }
+}
+
+fn hashPassword(password:&str, salt:&[u8]) -> Vec<u8> {
+    let mut mac=Hmac::new(Sha256::new(), password.as_bytes());
+    let mut hash=vec![0; HASH_LENGTH];
+
+    pbkdf2(&mut mac, salt, HASH_ITERATIONS, &mut hash[..]);
+
+    hash
+}
+
+pub fn checkUserName(userName:&str) -> Result<(), String> {
+    if userName.len()<USER_NAME_LENGTH_MIN || userName.len()>USER_NAME_LENGTH_MAX {
+        return Err( format!("Length of user name must be from {} to {}", USER_NAME_LENGTH_MIN, USER_NAME_LENGTH_MAX) );
+    }
+
+    if !userName.chars().all(|c| c.is_alphanumeric() || c=='_' || c=='-') {
+        return Err( String::from("User name may contain only letters, digits, '_' and '-'") );
+    }
+
+    Ok(())
+}
+
+fn checkPassword(password:&str) -> Result<(), String> {
+    if password.len()<PASSWORD_LENGTH_MIN || password.len()>PASSWORD_LENGTH_MAX {
+        return Err( format!("Length of password must be from {} to {}", PASSWORD_LENGTH_MIN, PASSWORD_LENGTH_MAX) );
+    }
+
+    Ok(())
+}
@@ -1,14 +1,29 @@
-
-/*
 use std::thread;
-use std::sync::{Mutex,RwLock,Arc,Barrier,Weak};
+use std::sync::{Mutex,Arc,Weak};
 
-use std::process::{Command, Stdio};
-
-use std::io::{Write,Read, ErrorKind};
+use std::error::Error;
+use std::io::{Write, Read, ErrorKind};
 use nanomsg::{Socket, Protocol, Endpoint};
 
 use appData::AppData;
+use command::{Command, COMMANDS_HELP};
+
+/*
+server_admin запускает server_game и общается с ним через 2 nanomsg pipe:
+ToGS_<adminPort>.ipc - команды к нам, FromGS_<adminPort>.ipc - ответы и heartbeat-ы от нас.
+Сообщение имеет вид "тип:аргументы".
+
+Рукопожатие:
+мы -> answer:FromGS is ready
+админ -> answer:ToGS is ready
+мы -> answer:IPC is ready
+
+Потом каждые 500мс мы отправляем online:, чтобы админ знал, что сервер жив.
+cmd:stop останавливает сервер после отсчета, cmd:<команда> выполняется Server::executeCommand(см. command.rs), ответ приходит как print:<текст>.
+close: закрывает ToGS(его же мы отправляем сами себе, чтобы поток чтения не ждал вечно).
+*/
+
+const HEARTBEAT_INTERVAL: u32 = 500; //ms
 
 struct Channel{
     socket:Socket,
@@ -24,7 +39,7 @@ impl Channel{
 
         socket.set_receive_timeout(5000);
 
-        let mut endpoint = match socket.bind( fileName ){
+        let endpoint = match socket.bind( fileName ){
             Ok(  s )=>s,
             Err( e )=>return Err( format!("Can not create endpoint \"{}\" : {}", fileName, e.description()) ),
         };
@@ -45,7 +60,7 @@ impl Channel{
 
         socket.set_send_timeout(200);
 
-        let mut endpoint = match socket.connect( fileName ){
+        let endpoint = match socket.connect( fileName ){
             Ok(  s )=>s,
             Err( e )=>return Err(format!("Can not create endpoint \"{}\" : {}", fileName, e.description())),
         };
@@ -75,11 +90,13 @@ pub struct AdminServer{
 
 impl AdminServer{
     pub fn connect( appData:Arc<AppData> ) -> Result<(),String> {
-        let toGSFileName=format!("ipc:///tmp/ToGS_{}.ipc",appData.port);
+        let port=appData.serverConfig.server_adminPort;
+
+        let toGSFileName=format!("ipc:///tmp/ToGS_{}.ipc",port);
         let mut toGS=try!(Channel::newPull( &toGSFileName ));
 
         //==========================FromGS====================
-        let fromGSFileName=format!("ipc:///tmp/FromGS_{}.ipc",appData.port);
+        let fromGSFileName=format!("ipc:///tmp/FromGS_{}.ipc",port);
         let mut fromGS=try!(Channel::newPush( &fromGSFileName ));
 
         fromGS.socket.set_send_timeout(5000);
@@ -138,7 +155,7 @@ impl AdminServer{
             let thread_adminServer=adminServer.clone();
             let threadJoin=thread::spawn(move || {
                 loop{
-                    for i in 0..10 {
+                    for _ in 0..HEARTBEAT_INTERVAL/50 {
                         thread::sleep_ms(50);
 
                         if {*thread_adminServer.shouldClose.lock().unwrap()} {
@@ -150,6 +167,8 @@ impl AdminServer{
                 }
             });
 
+            let mut isLost=false;
+
             while !{*adminServer.shouldClose.lock().unwrap()} {
                 match toGS.socket.read_to_string(&mut msg){
                     Ok( _ ) => {
@@ -160,16 +179,17 @@ impl AdminServer{
                         let v: Vec<&str> = msg.splitn(2, ':').collect();
 
                         if v.len()==2{
-                            AdminServer::processToGSCommand( &appData, v[0], v[1] );
+                            AdminServer::processToGSCommand( &appData, &adminServer, v[0], v[1] );
                         }else{
-                            appData.log.print( format!("[ERROR]ToGS: \"{}\" is no command", msg.as_str()) );
+                            appData.log.print( format!("[ERROR] ToGS: \"{}\" is no command", msg.as_str()) );
                         }
                     },
                     Err( e ) => {
                         match e.kind() {
                             ErrorKind::TimedOut => {},
                             _=> {
-                                appData.log.print( format!("[ERROR]ToGS read error : {}", e.description()) );
+                                appData.log.print( format!("[ERROR] ToGS read error : {}", e.description()) );
+                                isLost=true;
                                 break;
                             }
                         }
@@ -184,33 +204,66 @@ impl AdminServer{
 
             *adminServer.isRunning.lock().unwrap()=false;
 
-            //Выжидает, когда gameServer-ом никто не пользуется, и делает недоступным его использование
+            //Выжидает, когда adminServer-ом никто не пользуется, и делает недоступным его использование
             *appData.adminServer.write().unwrap()=None;
-            appData.log.print(format!("[INFO]Admin server connection has been closed"));
+            appData.log.print(format!("[INFO] Admin server connection has been closed"));
+
+            //без server_admin сервер никто не остановит
+            if isLost {
+                *appData.shouldStop.write().unwrap()=true;
+            }
             //AdminServer разрушается автоматически
         });
     }
 
-    fn processToGSCommand( appData:&Arc<AppData>, commandType:&str, args:&str ){
+    fn processToGSCommand( appData:&Arc<AppData>, adminServer:&AdminServer, commandType:&str, args:&str ){
         match commandType {
-            //"answer" => *answer.lock().unwrap()=Some(String::from(v[1])),
-            //"print" => appData.log.print( String::from(args) ),
             "cmd" => {
                 match args{
-                    "stop" => *appData.shouldStop.write().unwrap()=true,
-                    _=>{},
+                    "help" => {adminServer.send("print", COMMANDS_HELP);},
+                    _=>{
+                        let result=match Command::parse(args) {
+                            Ok ( command ) => {
+                                //клонируем, чтобы не держать блокировку appData.server, пока ждем ответ
+                                let server=(*appData.server.read().unwrap()).clone();
+
+                                match server {
+                                    Some( server ) => server.executeCommand(command),
+                                    None => Err( String::from("Server is not running") ),
+                                }
+                            },
+                            Err( e ) => Err(e),
+                        };
+
+                        match result {
+                            Ok ( answer ) => {adminServer.send("print", &answer);},
+                            Err( e ) => {adminServer.send("print", &format!("Error: {}", e));},
+                        }
+                    },
                 }
             },
-            _=>appData.log.print( format!("[ERROR]ToGS: unknown command\"{}\"", args) ),
+            _=>appData.log.print( format!("[ERROR] ToGS: unknown type of message \"{}\"", commandType) ),
         }
     }
 
     fn close(&self){
         *self.shouldClose.lock().unwrap()=true;
-        let mut toGSTerminator_socket = Socket::new(Protocol::Push).unwrap();
-        toGSTerminator_socket.set_send_timeout(2000);
-        let mut toGSTerminator_endpoint = toGSTerminator_socket.connect(&self.toGSFileName).unwrap();
-        toGSTerminator_socket.write(b"close:").unwrap();
+
+        //будим поток чтения ToGS
+        match Socket::new(Protocol::Push) {
+            Ok ( mut toGSTerminator_socket ) => {
+                toGSTerminator_socket.set_send_timeout(2000);
+
+                match toGSTerminator_socket.connect(&self.toGSFileName) {
+                    Ok ( mut toGSTerminator_endpoint ) => {
+                        toGSTerminator_socket.write(b"close:");
+                        toGSTerminator_endpoint.shutdown();
+                    },
+                    Err( _ ) => {},
+                }
+            },
+            Err( _ ) => {},
+        }
 
         while {*self.isRunning.lock().unwrap()} {
             thread::sleep_ms(100);
@@ -222,83 +275,17 @@ impl AdminServer{
 
         match {self.fromGS.lock().unwrap().socket.write( msg.as_bytes() )} {
             Ok ( _ ) => Ok(()),
-            Err( e ) => {
-                let errorMessage=format!("[ERROR]FromGS Write error : {}",e.description());
-                //self.appData.upgrade().unwrap().log.print(errorMessage.clone());
-                //not to admin
-
-                self.close();
-
-                Err( errorMessage )
-            },
+            Err( e ) => Err( format!("FromGS write error : {}",e.description()) ),
         }
     }
 
     pub fn stop(&self){
         let appData=self.appData.upgrade().unwrap();
 
-        appData.log.print(format!("[INFO]Stopping game server"));
+        appData.log.print(format!("[INFO] Closing admin server connection"));
 
         self.send("close","");
 
         self.close();
-
-        appData.log.print(format!("[INFO]Game server has been stoped"));
     }
 }
-*/
-
-
-/*
-use std::thread;
-use std::sync::{Mutex,RwLock,Arc,Barrier,Weak};
-
-use std::process::{Command, Stdio};
-
-use std::io::{Write,Read};
-
-use toGS::ToGS;
-use fromGS::FromGS;
-use appData::AppData;
-
-pub struct AdminServer{
-    appData:    Weak<AppData>,
-    toGS:       ToGS,
-    fromGS:     FromGS,
-}
-
-impl AdminServer{
-    pub fn run( appData:Arc<AppData> ) -> Result<(),String> {
-        //appData.log.print(format!("Running game server"))
-        let toGS=try!(ToGS::new( appData.clone() ));
-
-        let fromGS=try!(FromGS::new( appData.clone() ));
-
-        try!(fromGS.send("answer", "FromGS is ready"));
-
-        println!("1");
-
-        try!(toGS.waitAnswer("ToGS is ready"));
-
-        println!("2");
-
-        try!(fromGS.send("answer", "IPC is ready"));
-
-        println!("yeah");
-
-        thread::sleep_ms( 10000 );
-
-        let adminServer=Arc::new(
-            AdminServer{
-                appData:Arc::downgrade(&appData),
-                fromGS:fromGS,
-                toGS:toGS,
-            }
-        );
-
-        *appData.adminServer.write().unwrap()=Some(adminServer);
-
-        Ok(())
-    }
-}
-*/
@@ -1,6 +1,6 @@
 use std::sync::{Mutex,RwLock,Arc,Barrier,Weak};
 
-//use adminServer::AdminServer;
+use adminServer::AdminServer;
 use log::Log;
 use serverConfig::ServerConfig;
 use gameState::GameState;
@@ -8,6 +8,7 @@ use storage::Storage;
 use httpRequester::HTTPRequester;
 use server::Server;
 use map::Map;
+use masterServer::MasterServers;
 
 
 pub struct AppData{
@@ -15,31 +16,35 @@ pub struct AppData{
     pub serverConfig:ServerConfig,
     pub isEditor:bool,
     pub gameState:RwLock<GameState>,
+    pub masterServers:MasterServers,
 
     pub storage:RwLock<Option<Arc<Storage>>>,
     pub httpRequester:RwLock<Option<Arc<HTTPRequester>>>,
     pub server: RwLock<Option<Arc<Server>>>,
     pub map:    RwLock<Option<Arc<Map>>>,
 
-    //pub adminServer:RwLock< Option< Arc<AdminServer> > >,
-    //pub shouldStop:RwLock<bool>,
+    pub adminServer:RwLock< Option< Arc<AdminServer> > >,
+    pub shouldStop:RwLock<bool>, //main thread waits for it, then destroys AppData
 }
 
 impl AppData{
     pub fn initialize( serverConfig:ServerConfig, log:Log, isEditor:bool ) -> Arc<AppData> {
+        let masterServers=MasterServers::new(&serverConfig);
+
         let appData=AppData{
             log:log,
             serverConfig:serverConfig,
             isEditor:isEditor,
             gameState:RwLock::new(GameState::Initializing),
+            masterServers:masterServers,
 
             storage:RwLock::new(None),
             httpRequester:RwLock::new(None),
             server: RwLock::new(None),
             map:    RwLock::new(None),
 
-            //adminServer:RwLock::new(None),
-            //shouldStop:RwLock::new(false),
+            adminServer:RwLock::new(None),
+            shouldStop:RwLock::new(false),
         };
 
         Arc::new(appData)
@@ -69,6 +74,15 @@ impl AppData{
             Some ( m ) => Storage::destroy(m),
             None=>{},
         }
+
+        //==================Close admin server connection==================
+        //последним, чтобы server_admin узнал, что сервер действительно остановлен
+        let adminServer=(*appData.adminServer.read().unwrap()).clone();
+
+        match adminServer{
+            Some ( a ) => a.stop(),
+            None=>{},
+        }
     }
 
     pub fn getHTTPRequesterAnd<T,F>(&self, f:F) -> T where F:FnOnce(&HTTPRequester) -> T {
@@ -80,6 +94,15 @@ impl AppData{
         }
     }
 
+    pub fn getStorageAnd<T,F>(&self, f:F) -> T where F:FnOnce(&Storage) -> T {
+        match *self.storage.read().unwrap(){
+            Some( ref storage) => {
+                f( storage )
+            },
+            None=>panic!("No storage"),
+        }
+    }
+
     pub fn getServerAnd<T,F>(&self, f:F) -> T where F:FnOnce(&Server) -> T {
         match *self.server.read().unwrap(){
             Some( ref server) => {
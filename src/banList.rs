@@ -0,0 +1,375 @@
+use std::fs::{self, File};
+use std::error::Error;
+use std::io::{Read, Write, ErrorKind};
+
+use std::sync::RwLock;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::SystemTime;
+
+use time::{get_time, at_utc, Timespec};
+
+use description;
+use accounts::checkUserName;
+
+/*
+Бан-лист хранится в bans.list в формате description:
+bans = [ { userID = "1073741825", expires = "0", reason = "griefing" }
+{ userName = "Griefer", expires = "1700000000", reason = "spam" }
+{ ip = "10.0.0.0/8", expires = "0", reason = "" } ]
+expires - unix время в секундах, 0 - навсегда.
+Файл можно править руками, TCPServer перечитывает его, когда меняется время модификации.
+Каждое изменение увеличивает version, по нему TCPServer и Game понимают, что пора проверить тех, кто уже подключен.
+*/
+
+const BANS_FILE_NAME: &'static str = "bans.list";
+
+#[derive(Clone, PartialEq)]
+pub enum BanTarget{
+    UserID( usize ),
+    UserName( String ), //compared without case
+    IP( IpAddr, u8 ), //network, prefix length
+}
+
+#[derive(Clone)]
+pub struct Ban{
+    pub target:BanTarget,
+    pub expires:i64, //sec, 0 - forever
+    pub reason:String,
+}
+
+struct Bans{
+    list:Vec<Ban>,
+    modificationTime:Option<SystemTime>,
+    version:usize,
+}
+
+pub struct BanList{
+    bans:RwLock<Bans>,
+}
+
+impl BanTarget{
+    ///kind is userID, name or ip, ip may be written with prefix length like 10.0.0.0/8
+    pub fn parse(kind:&str, value:&str) -> Result<BanTarget, String> {
+        match kind {
+            "userID" => {
+                match value.parse::<usize>() {
+                    Ok ( userID ) => Ok( BanTarget::UserID(userID) ),
+                    Err( _ ) => Err( format!("Invalid userID \"{}\"", value) ),
+                }
+            },
+            "name" | "userName" => {
+                //имя пишется в bans.list как есть, поэтому допускаем только то, что допустимо для имени аккаунта
+                try!(checkUserName(value));
+
+                Ok( BanTarget::UserName( String::from(value) ) )
+            },
+            "ip" => {
+                let mut parts=value.splitn(2, '/');
+
+                let ip=match IpAddr::from_str( parts.next().unwrap_or("") ) {
+                    Ok ( ip ) => ip,
+                    Err( _ ) => return Err( format!("Invalid IP \"{}\"", value) ),
+                };
+
+                let maxPrefixLength=match ip {
+                    IpAddr::V4( _ ) => 32,
+                    IpAddr::V6( _ ) => 128,
+                };
+
+                let prefixLength=match parts.next() {
+                    Some( prefixLength ) => {
+                        match prefixLength.parse::<u8>() {
+                            Ok ( prefixLength ) if prefixLength<=maxPrefixLength => prefixLength,
+                            _ => return Err( format!("Invalid prefix length of \"{}\"", value) ),
+                        }
+                    },
+                    None => maxPrefixLength,
+                };
+
+                Ok( BanTarget::IP(ip, prefixLength) )
+            },
+            _ => Err( format!("Unknown type of ban \"{}\", expected userID, name or ip", kind) ),
+        }
+    }
+
+    pub fn print(&self) -> String {
+        match *self {
+            BanTarget::UserID( userID ) => format!("userID {}", userID),
+            BanTarget::UserName( ref userName ) => format!("name \"{}\"", userName),
+            BanTarget::IP( ref ip, prefixLength ) => format!("ip {}/{}", ip, prefixLength),
+        }
+    }
+
+    fn matchesIP(&self, address:&IpAddr) -> bool {
+        match *self {
+            BanTarget::IP( ref network, prefixLength ) => {
+                let (networkOctets, addressOctets)=match (*network, *address) {
+                    (IpAddr::V4( ref n ), IpAddr::V4( ref a )) => (n.octets().to_vec(), a.octets().to_vec()),
+                    (IpAddr::V6( ref n ), IpAddr::V6( ref a )) => (n.octets().to_vec(), a.octets().to_vec()),
+                    _ => return false,
+                };
+
+                let prefixLength=prefixLength as usize;
+
+                for i in 0..networkOctets.len() {
+                    let bits=if prefixLength>=(i+1)*8 {
+                        8
+                    }else if prefixLength>i*8 {
+                        prefixLength-i*8
+                    }else{
+                        break;
+                    };
+
+                    let mask=(0xFFu16 << (8-bits)) as u8;
+
+                    if networkOctets[i] & mask != addressOctets[i] & mask {
+                        return false;
+                    }
+                }
+
+                true
+            },
+            _ => false,
+        }
+    }
+
+    fn matchesUser(&self, userID:usize, userName:&str) -> bool {
+        match *self {
+            BanTarget::UserID( id ) => id==userID,
+            BanTarget::UserName( ref name ) => name.to_lowercase()==userName.to_lowercase(),
+            BanTarget::IP( _, _ ) => false,
+        }
+    }
+}
+
+impl Ban{
+    fn isActive(&self, time:i64) -> bool {
+        self.expires==0 || self.expires>time
+    }
+
+    ///Text for client and log
+    pub fn print(&self) -> String {
+        let expires=if self.expires==0 {
+            String::from("forever")
+        }else{
+            format!("until {}", at_utc( Timespec::new(self.expires, 0) ).rfc3339())
+        };
+
+        if self.reason.len()>0 {
+            format!("You are banned {} : {}", expires, self.reason)
+        }else{
+            format!("You are banned {}", expires)
+        }
+    }
+}
+
+impl BanList{
+    pub fn load() -> Result<BanList, String> {
+        let (list, modificationTime)=try!(readBans());
+
+        Ok(
+            BanList{
+                bans:RwLock::new(
+                    Bans{
+                        list:list,
+                        modificationTime:modificationTime,
+                        version:0,
+                    }
+                ),
+            }
+        )
+    }
+
+    ///Rereads file, if it has been changed by hand. Returns true, if bans have been reloaded
+    pub fn reloadIfChanged(&self) -> Result<bool, String> {
+        let modificationTime=getModificationTime();
+
+        if modificationTime=={self.bans.read().unwrap().modificationTime} {
+            return Ok(false);
+        }
+
+        let (list, modificationTime)=try!(readBans());
+
+        let mut bans=self.bans.write().unwrap();
+        bans.list=list;
+        bans.modificationTime=modificationTime;
+        bans.version+=1;
+
+        Ok(true)
+    }
+
+    ///Increases on each change of bans
+    pub fn getVersion(&self) -> usize {
+        self.bans.read().unwrap().version
+    }
+
+    pub fn checkIP(&self, ip:&IpAddr) -> Option<Ban> {
+        let time=get_time().sec;
+
+        for ban in self.bans.read().unwrap().list.iter() {
+            if ban.isActive(time) && ban.target.matchesIP(ip) {
+                return Some(ban.clone());
+            }
+        }
+
+        None
+    }
+
+    pub fn checkUser(&self, userID:usize, userName:&str) -> Option<Ban> {
+        let time=get_time().sec;
+
+        for ban in self.bans.read().unwrap().list.iter() {
+            if ban.isActive(time) && ban.target.matchesUser(userID, userName) {
+                return Some(ban.clone());
+            }
+        }
+
+        None
+    }
+
+    ///Replaces ban of the same target
+    pub fn add(&self, ban:Ban) -> Result<(), String> {
+        let mut bans=self.bans.write().unwrap();
+
+        bans.list.retain(|b| b.target!=ban.target);
+        bans.list.push(ban);
+
+        saveBans(&mut bans)
+    }
+
+    pub fn remove(&self, target:&BanTarget) -> Result<bool, String> {
+        let mut bans=self.bans.write().unwrap();
+
+        let bansNumber=bans.list.len();
+        bans.list.retain(|b| b.target!=*target);
+
+        if bans.list.len()==bansNumber {
+            return Ok(false);
+        }
+
+        try!(saveBans(&mut bans));
+
+        Ok(true)
+    }
+
+    pub fn print(&self) -> String {
+        let time=get_time().sec;
+        let bans=self.bans.read().unwrap();
+
+        let mut list=String::new();
+        let mut bansNumber=0;
+
+        for ban in bans.list.iter() {
+            if !ban.isActive(time) {
+                continue;
+            }
+
+            bansNumber+=1;
+
+            let expires=if ban.expires==0 {
+                String::from("forever")
+            }else{
+                format!("until {}", at_utc( Timespec::new(ban.expires, 0) ).rfc3339())
+            };
+
+            list.push_str( &format!("\n{} {} : {}", ban.target.print(), expires, ban.reason) );
+        }
+
+        format!("{} bans{}", bansNumber, list)
+    }
+}
+
+fn getModificationTime() -> Option<SystemTime> {
+    match fs::metadata(BANS_FILE_NAME) {
+        Ok ( metadata ) => metadata.modified().ok(),
+        Err( _ ) => None,
+    }
+}
+
+fn readBans() -> Result<(Vec<Ban>, Option<SystemTime>), String> {
+    let modificationTime=getModificationTime();
+
+    let mut file=match File::open(BANS_FILE_NAME) {
+        Ok( f ) => f,
+        Err( ref e ) if e.kind()==ErrorKind::NotFound => return Ok( (Vec::new(), None) ),
+        Err( e ) => return Err(format!("Can not read file \"{}\" : {}", BANS_FILE_NAME, e.description())),
+    };
+
+    let mut content = String::new();
+    match file.read_to_string(&mut content){
+        Ok( _ )  => {},
+        Err( e ) => return Err(format!("Can not read file \"{}\" : {}", BANS_FILE_NAME, e.description())),
+    }
+
+    if content.trim().len()==0 {
+        return Ok( (Vec::new(), modificationTime) );
+    }
+
+    let list=try!(description::parse( &content, |root| {
+        let mut list=Vec::new();
+
+        for banValue in try!(root.getList("bans")).iter() {
+            let banMap=try!(banValue.getMap());
+
+            let target=if let Ok( userID ) = banMap.getString("userID") {
+                try!(BanTarget::parse("userID", userID))
+            }else if let Ok( userName ) = banMap.getString("userName") {
+                try!(BanTarget::parse("userName", userName))
+            }else{
+                try!(BanTarget::parse("ip", try!(banMap.getString("ip"))))
+            };
+
+            list.push(
+                Ban{
+                    target:target,
+                    expires:try!(banMap.getStringAs::<i64>("expires")),
+                    reason:try!(banMap.getString("reason")).clone(),
+                }
+            );
+        }
+
+        Ok(list)
+    }).or_else(|e| Err(format!("File \"{}\" : {}", BANS_FILE_NAME, e))));
+
+    Ok( (list, modificationTime) )
+}
+
+//истекшие баны не сохраняем
+fn saveBans(bans:&mut Bans) -> Result<(), String> {
+    let time=get_time().sec;
+
+    bans.list.retain(|ban| ban.isActive(time));
+
+    let mut content=String::from("bans = [");
+
+    for (i, ban) in bans.list.iter().enumerate() {
+        if i>0 {
+            content.push_str("\n");
+        }
+
+        let target=match ban.target {
+            BanTarget::UserID( userID ) => format!("userID = \"{}\"", userID),
+            BanTarget::UserName( ref userName ) => format!("userName = \"{}\"", userName),
+            BanTarget::IP( ref ip, prefixLength ) => format!("ip = \"{}/{}\"", ip, prefixLength),
+        };
+
+        //в description нет экранирования, поэтому кавычки из причины убираем
+        let reason=ban.reason.replace('"', "'");
+
+        content.push_str( &format!(" {{ {}, expires = \"{}\", reason = \"{}\" }}", target, ban.expires, reason) );
+    }
+
+    content.push_str(" ]\n");
+
+    let mut file=try!(File::create(BANS_FILE_NAME)
+        .or_else(|e| Err(format!("Can not write file \"{}\" : {}", BANS_FILE_NAME, e.description()))));
+
+    try!(file.write_all(content.as_bytes())
+        .or_else(|e| Err(format!("Can not write file \"{}\" : {}", BANS_FILE_NAME, e.description()))));
+
+    bans.modificationTime=getModificationTime();
+    bans.version+=1;
+
+    Ok(())
+}
@@ -0,0 +1,159 @@
+use std::sync::mpsc;
+
+use banList::BanTarget;
+
+/*
+Команды оператора. Приходят из pipe server_admin(cmd:<команда>) или из консоли,
+Server::executeCommand отправляет их потокам, которые владеют нужными данными, и ждет ответ.
+Игроки тоже могут отправлять команды(ClientToServerTCPPacket::Command), если это позволяет их роль(roles.rs).
+*/
+
+pub type CommandResult = Result<String, String>;
+
+///Thread of server answers to command through it
+pub type CommandReply = mpsc::Sender<CommandResult>;
+
+///Longer bans are forever(0)
+pub const BAN_DURATION_LIMIT: i64 = 100*365*24*60; //minutes
+pub const STOP_COUNTDOWN_LIMIT: u64 = 24*60*60; //sec
+
+pub const COMMANDS_HELP: &'static str = "connections - list TCP connections and their stages
+players - list players
+kick <playerID|name> [reason] - disconnect player
+broadcast <text> - send notice to all players
+playersLimit <number> - change limit of players
+ban <userID|name|ip> <value> <minutes, 0 - forever> [reason] - ban and disconnect, ip may be like 10.0.0.0/8
+unban <userID|name|ip> <value> - remove ban
+bans - list bans
+stop [seconds] - stop server after countdown, stop 0 - immediately
+cancelStop - cancel scheduled stop";
+
+#[derive(Clone)]
+pub enum PlayerSelector{
+    ID( usize ),
+    Name( String ),
+}
+
+impl PlayerSelector{
+    pub fn print(&self) -> String {
+        match *self {
+            PlayerSelector::ID( playerID ) => format!("{}", playerID),
+            PlayerSelector::Name( ref name ) => format!("\"{}\"", name),
+        }
+    }
+}
+
+pub enum Command{
+    ListConnections,
+    ListPlayers,
+    Kick( PlayerSelector, String ), //player, reason
+    Broadcast( String ),
+    SetPlayersLimit( usize ),
+    Ban( BanTarget, i64, String ), //target, duration in minutes(0 - forever), reason
+    Unban( BanTarget ),
+    ListBans,
+    Stop( Option<u64> ), //seconds before stop, by default shutdown.countdown
+    CancelStop,
+}
+
+impl Command{
+    ///The first word of command, roles(roles.cfg) permit commands by it
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Command::ListConnections => "connections",
+            Command::ListPlayers => "players",
+            Command::Kick( _, _ ) => "kick",
+            Command::Broadcast( _ ) => "broadcast",
+            Command::SetPlayersLimit( _ ) => "playersLimit",
+            Command::Ban( _, _, _ ) => "ban",
+            Command::Unban( _ ) => "unban",
+            Command::ListBans => "bans",
+            Command::Stop( _ ) => "stop",
+            Command::CancelStop => "cancelStop",
+        }
+    }
+
+    pub fn parse(line:&str) -> Result<Command, String> {
+        let (name, args)=splitCommand(line);
+
+        match name {
+            "connections" => Ok( Command::ListConnections ),
+            "players" => Ok( Command::ListPlayers ),
+            "kick" => {
+                let (player, reason)=splitCommand(args);
+
+                if player.len()==0 {
+                    return Err( String::from("usage: kick <playerID|name> [reason]") );
+                }
+
+                let selector=match player.parse::<usize>() {
+                    Ok ( playerID ) => PlayerSelector::ID(playerID),
+                    Err( _ ) => PlayerSelector::Name( String::from(player) ),
+                };
+
+                let reason=if reason.len()>0 {
+                    String::from(reason)
+                }else{
+                    String::from("Kicked by operator")
+                };
+
+                Ok( Command::Kick(selector, reason) )
+            },
+            "broadcast" => {
+                if args.len()==0 {
+                    return Err( String::from("usage: broadcast <text>") );
+                }
+
+                Ok( Command::Broadcast( String::from(args) ) )
+            },
+            "playersLimit" => {
+                match args.parse::<usize>() {
+                    Ok ( limit ) => Ok( Command::SetPlayersLimit(limit) ),
+                    Err( _ ) => Err( String::from("usage: playersLimit <number>") ),
+                }
+            },
+            "ban" => {
+                let (kind, args)=splitCommand(args);
+                let (value, args)=splitCommand(args);
+                let (duration, reason)=splitCommand(args);
+
+                let duration=match duration.parse::<i64>() {
+                    Ok ( duration ) if duration>=0 && duration<=BAN_DURATION_LIMIT => duration,
+                    Ok ( duration ) if duration>BAN_DURATION_LIMIT => return Err( format!("Ban duration must not be more than {} minutes, use 0 for forever", BAN_DURATION_LIMIT) ),
+                    _ => return Err( String::from("usage: ban <userID|name|ip> <value> <minutes, 0 - forever> [reason]") ),
+                };
+
+                Ok( Command::Ban( try!(BanTarget::parse(kind, value)), duration, String::from(reason) ) )
+            },
+            "unban" => {
+                let (kind, value)=splitCommand(args);
+
+                Ok( Command::Unban( try!(BanTarget::parse(kind, value)) ) )
+            },
+            "bans" => Ok( Command::ListBans ),
+            "stop" => {
+                if args.len()==0 {
+                    return Ok( Command::Stop(None) );
+                }
+
+                match args.parse::<u64>() {
+                    Ok ( seconds ) if seconds<=STOP_COUNTDOWN_LIMIT => Ok( Command::Stop( Some(seconds) ) ),
+                    Ok ( _ ) => Err( format!("Countdown must not be more than {} seconds", STOP_COUNTDOWN_LIMIT) ),
+                    Err( _ ) => Err( String::from("usage: stop [seconds]") ),
+                }
+            },
+            "cancelStop" => Ok( Command::CancelStop ),
+            _ => Err( format!("Unknown command \"{}\"", name) ),
+        }
+    }
+}
+
+///Splits line into the first word and the rest
+pub fn splitCommand(line:&str) -> (&str, &str) {
+    let line=line.trim();
+
+    match line.find(char::is_whitespace) {
+        Some( index ) => (&line[..index], line[index..].trim()),
+        None => (line, ""),
+    }
+}
@@ -0,0 +1,141 @@
+use std::thread;
+use std::sync::Arc;
+
+use std::io;
+use std::io::BufRead;
+
+use appData::AppData;
+use serverConfig::ServerConfig;
+use log::LogLevel;
+use server::ServerState;
+use command::{Command, CommandResult, COMMANDS_HELP, splitCommand};
+
+/*
+Консоль читает команды из stdin в своем потоке.
+Поток не join-ится: если stdin закрыт или это не терминал, read_line вернет 0 и поток просто завершится,
+а если он ждет ввода, процесс все равно завершится, когда main вернется.
+Команды сервера(command.rs) выполняются через Server::executeCommand, как и команды server_admin.
+*/
+
+const CONSOLE_HELP: &'static str = "status (st) - state of server
+players (p) - list players
+kick (k) <playerID|name> [reason] - disconnect player
+say (s) <text> - send notice to all players
+stop (q) [seconds] - stop server after countdown, stop 0 - immediately
+reload config (rc) - apply playersLimit and repositories from serverConfig.cfg
+log level (ll) <error|warning|info> - change level of log
+help (h, ?) - this list";
+
+pub struct Console;
+
+impl Console{
+    pub fn start( appData:Arc<AppData> ) {
+        thread::spawn(move || {
+            let stdin=io::stdin();
+            let mut line=String::with_capacity(256);
+
+            loop {
+                line.clear();
+
+                match stdin.lock().read_line(&mut line) {
+                    Ok ( 0 ) => return, //stdin закрыт
+                    Ok ( _ ) => {},
+                    Err( _ ) => return,
+                }
+
+                if line.trim().len()==0 {
+                    continue;
+                }
+
+                match Console::processCommand(&appData, &line) {
+                    Ok ( answer ) => println!("{}", answer),
+                    Err( e ) => println!("Error: {}", e),
+                }
+            }
+        });
+    }
+
+    fn processCommand( appData:&Arc<AppData>, line:&str ) -> CommandResult {
+        let (name, args)=splitCommand(line);
+
+        match name {
+            "help" | "h" | "?" => Ok( format!("{}\n{}", CONSOLE_HELP, COMMANDS_HELP) ),
+            "status" | "st" => Ok( Console::status(appData) ),
+            "players" | "p" => Console::executeCommand(appData, Command::ListPlayers),
+            "kick" | "k" => Console::executeCommand(appData, try!(Command::parse( &format!("kick {}", args) ))),
+            "say" | "s" => Console::executeCommand(appData, try!(Command::parse( &format!("broadcast {}", args) ))),
+            "stop" | "q" => Console::executeCommand(appData, try!(Command::parse( &format!("stop {}", args) ))),
+            "rc" => Console::reloadConfig(appData),
+            "ll" => Console::setLogLevel(appData, args),
+            "reload" => {
+                match splitCommand(args) {
+                    ("config", _) => Console::reloadConfig(appData),
+                    _ => Err( String::from("usage: reload config") ),
+                }
+            },
+            "log" => {
+                match splitCommand(args) {
+                    ("level", level) => Console::setLogLevel(appData, level),
+                    _ => Err( String::from("usage: log level <error|warning|info>") ),
+                }
+            },
+            _ => Console::executeCommand(appData, try!(Command::parse(line))),
+        }
+    }
+
+    fn executeCommand( appData:&Arc<AppData>, command:Command ) -> CommandResult {
+        //клонируем, чтобы не держать блокировку appData.server, пока ждем ответ
+        let server=(*appData.server.read().unwrap()).clone();
+
+        match server {
+            Some( server ) => server.executeCommand(command),
+            None => Err( String::from("Server is not running") ),
+        }
+    }
+
+    fn status( appData:&Arc<AppData> ) -> String {
+        let server=(*appData.server.read().unwrap()).clone();
+
+        match server {
+            Some( server ) => {
+                let state=match *server.state.read().unwrap() {
+                    ServerState::Initialization( _ ) => "initialization",
+                    ServerState::Processing => "processing",
+                    ServerState::Shutdown => "shutdown",
+                    ServerState::TCPError | ServerState::UDPError | ServerState::GameError => "error",
+                };
+
+                format!("Server is {}, players {}/{}, log level {}",
+                    state,
+                    *server.playersCount.read().unwrap(),
+                    *server.playersLimit.read().unwrap(),
+                    appData.log.getLevel().print()
+                )
+            },
+            None => String::from("Server is not running"),
+        }
+    }
+
+    //Остальные параметры применяются только при запуске
+    fn reloadConfig( appData:&Arc<AppData> ) -> CommandResult {
+        let serverConfig=try!(ServerConfig::read());
+
+        let repositories=serverConfig.repositories.read().unwrap().clone();
+        appData.masterServers.reload(&repositories);
+        *appData.serverConfig.repositories.write().unwrap()=repositories;
+
+        try!(Console::executeCommand(appData, Command::SetPlayersLimit(serverConfig.server_playersLimit)));
+
+        appData.log.print( String::from("[INFO] Server configurations are reloaded") );
+
+        Ok( String::from("playersLimit and repositories have been applied, other parameters require restart") )
+    }
+
+    fn setLogLevel( appData:&Arc<AppData>, level:&str ) -> CommandResult {
+        let level=try!(LogLevel::parse(level));
+
+        appData.log.setLevel(level);
+
+        Ok( format!("Log level is {}", level.print()) )
+    }
+}
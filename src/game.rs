@@ -0,0 +1,507 @@
+use std::thread;
+use std::sync::Arc;
+use std::sync::mpsc;
+
+use time::{get_time};
+use std::time::Duration;
+
+use appData::AppData;
+use server::{Server, DisconnectionReason, getServerTime};
+use server::ServerState;
+
+use player::Player;
+use tcpServer::TCPServerMessage;
+
+use packet::{ClientToServerTCPPacket, ClientToServerUDPPacket, ServerToClientTCPPacket, DisconnectionCode};
+use command::{Command, CommandReply, CommandResult, PlayerSelector};
+use worldStore::PlayerRecord;
+
+pub enum GameMessage{
+    AddPlayer( usize, usize, String ), //playerID, userID, userName
+    TCPPacket( usize, ClientToServerTCPPacket ),
+    UDPPacket( usize, ClientToServerUDPPacket, u64, u64 ), //playerID, packet, time of client, time of server, when it has been received
+    Disconnect( usize, DisconnectionReason ),
+    PlayerDetached( usize ), //TCP connection has been lost, player waits for resume
+    PlayerResumed( usize ),
+    ListPlayers( CommandReply ),
+    Kick( PlayerSelector, String, CommandReply ), //player, reason
+    Broadcast( String ),
+    ScheduleShutdown( u64 ), //seconds
+    CancelShutdown,
+}
+
+const PLAYER_COMMANDS_QUEUE_LIMIT: usize = 16;
+
+pub struct Game{
+    pub appData:Arc<AppData>,
+    pub server:Arc<Server>,
+
+    receiver:mpsc::Receiver<GameMessage>,
+    players:Vec<Option<Player>>, //index is playerID
+    tickTime:i64,
+    bansVersion:usize, //when version of ban list changes, players are checked again
+    shutdownTime:Option<u64>, //ms, server time of scheduled shutdown
+    nextShutdownWarning:usize, //index in shutdown_warnings
+    isWorldSaved:bool,
+    commandSender:mpsc::SyncSender<(usize, Command)>, //commands of players, which Game does not execute itself, playerID
+}
+
+impl Game{
+    pub fn new(appData:Arc<AppData>, server:Arc<Server>, receiver:mpsc::Receiver<GameMessage>) -> Game {
+        let connectionsLimit=appData.serverConfig.server_connectionsLimit;
+
+        let mut players=Vec::with_capacity(connectionsLimit);
+
+        for _ in 0..connectionsLimit {
+            players.push(None);
+        }
+
+        let bansVersion=appData.getStorageAnd(|storage| storage.banList.getVersion());
+
+        //Server::executeCommand ждет ответа, поэтому команды игроков выполняет один поток по очереди, а не поток на команду
+        let (commandSender, commandReceiver)=mpsc::sync_channel::<(usize, Command)>(PLAYER_COMMANDS_QUEUE_LIMIT);
+        let commandServer=server.clone();
+
+        thread::spawn(move || {
+            for (playerID, command) in commandReceiver.iter() {
+                let packet=match commandServer.executeCommand(command) {
+                    Ok ( answer ) => ServerToClientTCPPacket::CommandResult(true, answer),
+                    Err( e ) => ServerToClientTCPPacket::CommandResult(false, e),
+                };
+
+                Player::sendMessage(&commandServer, playerID, packet.pack());
+            }
+        });
+
+        Game{
+            appData:appData,
+            server:server,
+
+            receiver:receiver,
+            players:players,
+            tickTime:get_time().sec,
+            bansVersion:bansVersion,
+            shutdownTime:None,
+            nextShutdownWarning:0,
+            isWorldSaved:false,
+            commandSender:commandSender,
+        }
+    }
+
+    pub fn process(&mut self) -> Result<(), &'static str>{
+        if !self.server.onThreadInitialized() {
+            return Ok(());
+        }
+
+        self.appData.log.print(format!("[INFO] Game is ready"));
+
+        while {*self.server.state.read().unwrap()}==ServerState::Processing {
+            match self.receiver.recv_timeout( Duration::new(0,100_000_000) ) {
+                Ok ( message ) => self.processMessage(message),
+                Err( mpsc::RecvTimeoutError::Timeout ) => {},
+                Err( mpsc::RecvTimeoutError::Disconnected ) => return Err( "Game channel has been closed" ),
+            }
+
+            self.processShutdown();
+            self.processTick();
+        }
+
+        Ok(())
+    }
+
+    fn processMessage(&mut self, message:GameMessage){
+        match message {
+            GameMessage::AddPlayer( playerID, userID, userName ) => {
+                let role=self.appData.getStorageAnd(|storage| storage.roles.getRole(userID));
+
+                self.appData.log.print(format!("[INFO] Player {} \"{}\" has joined as {}", playerID, &userName, role.print()));
+
+                self.players[playerID]=Some( Player::new(self.server.clone(), playerID, userID, userName, role) );
+            },
+            GameMessage::TCPPacket( playerID, ClientToServerTCPPacket::Command( line ) ) =>
+                self.processPlayerCommand(playerID, line),
+            GameMessage::TCPPacket( playerID, packet ) => {
+                let result=match self.players[playerID] {
+                    Some( ref mut player ) => {
+                        if player.isActive {
+                            player.processMessage(&packet)
+                        }else{
+                            Ok(())
+                        }
+                    },
+                    None => Ok(()),
+                };
+
+                match result {
+                    Ok ( _ ) => {},
+                    Err( e ) => {
+                        match self.players[playerID] {
+                            Some( ref mut player ) => player.disconnect( DisconnectionReason::ServerError(DisconnectionCode::ProtocolError, e) ),
+                            None => {},
+                        }
+                    },
+                }
+            },
+            GameMessage::UDPPacket( playerID, packet, time, receiveTime ) => {
+                match self.players[playerID] {
+                    Some( ref mut player ) => {
+                        if player.isActive {
+                            player.processDatagram(&packet, time, receiveTime);
+                        }
+                    },
+                    None => {},
+                }
+            },
+            GameMessage::Disconnect( playerID, reason ) => {
+                match self.players[playerID] {
+                    Some( ref mut player ) => player._disconnect(reason),
+                    None => {},
+                }
+            },
+            GameMessage::PlayerDetached( playerID ) => {
+                match self.players[playerID] {
+                    Some( ref mut player ) => player.isConnected=false,
+                    None => {},
+                }
+            },
+            GameMessage::PlayerResumed( playerID ) => {
+                match self.players[playerID] {
+                    Some( ref mut player ) => player.isConnected=true,
+                    None => {},
+                }
+            },
+            GameMessage::ListPlayers( reply ) => {
+                reply.send( Ok( self.listPlayers() ) );
+            },
+            GameMessage::Kick( selector, reason, reply ) => {
+                reply.send( self.kick(selector, reason) );
+            },
+            GameMessage::Broadcast( text ) => self.broadcast(text),
+            GameMessage::ScheduleShutdown( seconds ) => {
+                let shutdownTime=match seconds.checked_mul(1000).and_then(|ms| getServerTime().checked_add(ms)) {
+                    Some( shutdownTime ) => shutdownTime,
+                    None => {
+                        self.appData.log.print( format!("[ERROR] Countdown of {} seconds is too long", seconds) );
+                        return;
+                    },
+                };
+
+                self.appData.log.print( format!("[INFO] Server will be stopped in {} seconds", seconds) );
+
+                self.shutdownTime=Some(shutdownTime);
+
+                //предупреждения для большего времени уже не нужны
+                let warnings=&self.appData.serverConfig.shutdown_warnings;
+                self.nextShutdownWarning=warnings.iter().position(|&w| w<seconds).unwrap_or(warnings.len());
+
+                self.server.sendToTCPServer( TCPServerMessage::SetAccepting(false) );
+
+                if seconds>0 {
+                    self.sendToAll( ServerToClientTCPPacket::ShutdownWarning(seconds) );
+                }
+            },
+            GameMessage::CancelShutdown => {
+                if self.shutdownTime.is_none() {
+                    return;
+                }
+
+                self.appData.log.print( String::from("[INFO] Stop of server has been cancelled") );
+
+                self.shutdownTime=None;
+
+                self.server.sendToTCPServer( TCPServerMessage::SetAccepting(true) );
+                self.sendToAll( ServerToClientTCPPacket::ShutdownCancelled );
+            },
+        }
+    }
+
+    //Команды, данные которых у Game, выполняются сразу, остальные - в потоке команд, тк Server::executeCommand ждет ответа
+    fn processPlayerCommand(&mut self, playerID:usize, line:String) {
+        let (role, userName)=match self.players[playerID] {
+            Some( ref player ) if player.isActive => (player.role, player.userName.clone()),
+            _ => return,
+        };
+
+        let command=match Command::parse(&line) {
+            Ok ( command ) => command,
+            Err( e ) => {
+                Player::sendMessage(&self.server, playerID, ServerToClientTCPPacket::CommandResult(false, e).pack());
+                return;
+            },
+        };
+
+        if !self.appData.getStorageAnd(|storage| storage.roles.isAllowed(role, command.name())) {
+            let answer=format!("Role {} does not allow command \"{}\"", role.print(), command.name());
+            Player::sendMessage(&self.server, playerID, ServerToClientTCPPacket::CommandResult(false, answer).pack());
+            return;
+        }
+
+        self.appData.log.print( format!("[INFO] Player {} \"{}\"({}) : {}", playerID, userName, role.print(), line) );
+
+        let result=match command {
+            Command::ListPlayers => Ok( self.listPlayers() ),
+            Command::Kick( selector, reason ) => self.kick(selector, reason),
+            Command::Broadcast( text ) => {
+                self.broadcast(text);
+                Ok( String::from("Notice has been sent") )
+            },
+            command => {
+                match self.commandSender.try_send( (playerID, command) ) {
+                    Ok ( _ ) => return,
+                    Err( mpsc::TrySendError::Full( _ ) ) => Err( String::from("Server is busy with other commands, try later") ),
+                    Err( mpsc::TrySendError::Disconnected( _ ) ) => Err( String::from("Commands are not executed anymore") ),
+                }
+            },
+        };
+
+        let packet=match result {
+            Ok ( answer ) => ServerToClientTCPPacket::CommandResult(true, answer),
+            Err( e ) => ServerToClientTCPPacket::CommandResult(false, e),
+        };
+
+        Player::sendMessage(&self.server, playerID, packet.pack());
+    }
+
+    fn listPlayers(&self) -> String {
+        let mut list=String::new();
+        let mut playersCount=0;
+
+        for player in self.players.iter() {
+            match *player {
+                Some( ref player ) => {
+                    if player.isActive {
+                        playersCount+=1;
+                        list.push_str( &format!("\n{} {} \"{}\" {} rtt {}ms{}", player.playerID, player.userID, player.userName, player.role.print(), player.rtt,
+                            if player.isConnected { "" } else { " detached" }
+                        ) );
+                    }
+                },
+                None => {},
+            }
+        }
+
+        format!("{} players{}", playersCount, list)
+    }
+
+    fn kick(&mut self, selector:PlayerSelector, reason:String) -> CommandResult {
+        match self.findPlayer(&selector) {
+            Some( playerID ) => {
+                match self.players[playerID] {
+                    Some( ref mut player ) => {
+                        self.appData.log.print( format!("[INFO] Player {} \"{}\" has been kicked : {}", playerID, player.userName, reason) );
+
+                        player.disconnect( DisconnectionReason::ServerDesire(DisconnectionCode::Kicked, reason) );
+
+                        Ok( format!("Player {} \"{}\" has been kicked", playerID, player.userName) )
+                    },
+                    None => Err( format!("No player {}", selector.print()) ),
+                }
+            },
+            None => Err( format!("No player {}", selector.print()) ),
+        }
+    }
+
+    fn broadcast(&self, text:String) {
+        self.appData.log.print( format!("[INFO] Notice : {}", text) );
+
+        self.sendToAll( ServerToClientTCPPacket::Notice(text) );
+    }
+
+    ///Returns playerID of active player. Names are compared without case
+    fn findPlayer(&self, selector:&PlayerSelector) -> Option<usize> {
+        match *selector {
+            PlayerSelector::ID( playerID ) => {
+                match self.players.get(playerID) {
+                    Some( &Some( ref player ) ) if player.isActive => Some(playerID),
+                    _ => None,
+                }
+            },
+            PlayerSelector::Name( ref name ) => {
+                let name=name.to_lowercase();
+
+                for player in self.players.iter() {
+                    match *player {
+                        Some( ref player ) => {
+                            if player.isActive && player.userName.to_lowercase()==name {
+                                return Some(player.playerID);
+                            }
+                        },
+                        None => {},
+                    }
+                }
+
+                None
+            },
+        }
+    }
+
+    fn sendToAll(&self, packet:ServerToClientTCPPacket) {
+        let message=packet.pack();
+
+        for player in self.players.iter() {
+            match *player {
+                Some( ref player ) => {
+                    if player.isActive {
+                        Player::sendMessage(&self.server, player.playerID, message.clone());
+                    }
+                },
+                None => {},
+            }
+        }
+    }
+
+    //Warns players, while time of shutdown comes. Then main thread stops server by AppData::destroy
+    fn processShutdown(&mut self) {
+        let shutdownTime=match self.shutdownTime {
+            Some( shutdownTime ) => shutdownTime,
+            None => return,
+        };
+
+        let time=getServerTime();
+
+        if time>=shutdownTime {
+            self.shutdownTime=None;
+
+            //аккаунты и бан-лист записываются сразу при изменении, остается мир
+            self.saveWorld();
+
+            self.appData.log.print( String::from("[INFO] Time of shutdown has come") );
+            *self.appData.shouldStop.write().unwrap()=true;
+
+            return;
+        }
+
+        let remainingTime=(shutdownTime-time+999)/1000;
+        let mut warning=None;
+
+        {
+            let warnings=&self.appData.serverConfig.shutdown_warnings;
+
+            while self.nextShutdownWarning<warnings.len() && warnings[self.nextShutdownWarning]>=remainingTime {
+                warning=Some(warnings[self.nextShutdownWarning]);
+                self.nextShutdownWarning+=1;
+            }
+        }
+
+        match warning {
+            Some( seconds ) => self.sendToAll( ServerToClientTCPPacket::ShutdownWarning(seconds) ),
+            None => {},
+        }
+    }
+
+    fn processTick(&mut self) {
+        if get_time().sec-self.tickTime>1 {
+            self.tickTime=get_time().sec;
+
+            self.checkBans();
+            self.checkPlayers();
+            self.synchronizeClocks();
+        }
+    }
+
+    fn synchronizeClocks(&mut self){
+        for player in self.players.iter_mut() {
+            match *player {
+                Some( ref mut player ) => {
+                    if player.isActive {
+                        player.synchronizeClock();
+                    }
+                },
+                None => {},
+            }
+        }
+    }
+
+    fn checkBans(&mut self){
+        let bansVersion=self.appData.getStorageAnd(|storage| storage.banList.getVersion());
+
+        if bansVersion==self.bansVersion {
+            return;
+        }
+
+        self.bansVersion=bansVersion;
+
+        let appData=self.appData.clone();
+
+        appData.getStorageAnd(|storage| {
+            for player in self.players.iter_mut() {
+                match *player {
+                    Some( ref mut player ) => {
+                        if !player.isActive {
+                            continue;
+                        }
+
+                        match storage.banList.checkUser(player.userID, &player.userName) {
+                            Some( ban ) => {
+                                appData.log.print( format!("[INFO] Player {} \"{}\" is banned", player.playerID, player.userName) );
+                                player.disconnect( DisconnectionReason::ServerDesire(DisconnectionCode::Banned, ban.print()) );
+                            },
+                            None => {},
+                        }
+                    },
+                    None => {},
+                }
+            }
+        });
+    }
+
+    fn checkPlayers(&mut self){
+        let mut playersCount=0;
+
+        for player in self.players.iter_mut() {
+            let removePlayer=match *player {
+                Some( ref player ) => !player.isActive,
+                None => false,
+            };
+
+            if removePlayer {
+                *player=None;
+            }else if player.is_some() {
+                playersCount+=1;
+            }
+        }
+
+        *self.server.playersCount.write().unwrap()=playersCount;
+    }
+
+    ///Players and map are written into Storage once, at scheduled shutdown or, if server is stopped otherwise, in onServerShutdown
+    fn saveWorld(&mut self) {
+        if self.isWorldSaved {
+            return;
+        }
+
+        self.isWorldSaved=true;
+
+        let time=get_time().sec;
+        let mut onlinePlayers=Vec::new();
+
+        for player in self.players.iter() {
+            match *player {
+                Some( ref player ) => onlinePlayers.push( PlayerRecord{ userID:player.userID, userName:player.userName.clone(), lastSeen:time } ),
+                None => {},
+            }
+        }
+
+        let map=self.appData.serverConfig.getMapDescription();
+
+        match self.appData.getStorageAnd(|storage| storage.world.save(&map, onlinePlayers)) {
+            Ok ( playersCount ) => self.appData.log.print( format!("[INFO] World has been saved, {} players", playersCount) ),
+            Err( e ) => self.appData.log.print( format!("[ERROR] Can not save world : {}", e) ),
+        }
+    }
+
+    pub fn onServerShutdown(&mut self) {
+        self.saveWorld();
+
+        for player in self.players.iter_mut() {
+            match *player {
+                Some( ref mut player ) => player._disconnect( DisconnectionReason::ServerShutdown ),
+                None => {},
+            }
+
+            *player=None;
+        }
+
+        *self.server.playersCount.write().unwrap()=0;
+    }
+}
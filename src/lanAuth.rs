@@ -0,0 +1,84 @@
+use std::collections::{HashMap, HashSet};
+
+use serverConfig::ServerConfig;
+use accounts::checkUserName;
+
+/*
+LAN режим(lan.enabled) для локальных тестов: главный сервер не используется,
+клиент вместо SessionID присылает имя(ClientToServerTCPPacket::LANName), а userID выдает сервер.
+Имена не должны повторяться среди подключенных и не должны быть зарезервированными.
+Сравниваем имена без учета регистра, чтобы нельзя было выдать себя за "Admin".
+userID получается из имени(FNV-1a от имени в нижнем регистре), поэтому после переподключения и перезапуска
+у игрока тот же userID, и роли(roles.cfg) и баны по userID относятся к нему, а не к тому, кто подключится следующим.
+*/
+
+///userID of LAN players begin with it, so they do not intersect with userID of master server and local accounts
+pub const LAN_USER_ID_BEGIN: usize = 0x6000_0000;
+const LAN_USER_ID_MASK: u32 = 0x1FFF_FFFF;
+
+pub struct LANAuth{
+    reservedNames:HashSet<String>,
+    names:HashMap<String, usize>, //name in lowercase, sessionID
+    sessionNames:HashMap<usize, String>,
+}
+
+impl LANAuth{
+    pub fn new(serverConfig:&ServerConfig) -> LANAuth {
+        let mut reservedNames=HashSet::new();
+
+        for name in serverConfig.lan_reservedNames.iter() {
+            reservedNames.insert(name.to_lowercase());
+        }
+
+        LANAuth{
+            reservedNames:reservedNames,
+            names:HashMap::new(),
+            sessionNames:HashMap::new(),
+        }
+    }
+
+    ///Occupies name for session and returns userID
+    pub fn acquireName(&mut self, sessionID:usize, userName:&str) -> Result<usize, String> {
+        try!(checkUserName(userName));
+
+        let name=userName.to_lowercase();
+
+        if self.reservedNames.contains(&name) {
+            return Err( format!("Name \"{}\" is reserved", userName) );
+        }
+
+        let userID=getUserID(&name);
+
+        //у разных имен может совпасть userID, тогда второе имя занять нельзя, пока первое на сервере
+        let isUsed=self.names.iter().any(|(usedName, &usedSessionID)| usedSessionID!=sessionID && (*usedName==name || getUserID(usedName)==userID));
+
+        if isUsed {
+            return Err( format!("Name \"{}\" is already used", userName) );
+        }
+
+        self.release(sessionID);
+
+        self.names.insert(name.clone(), sessionID);
+        self.sessionNames.insert(sessionID, name);
+
+        Ok(userID)
+    }
+
+    pub fn release(&mut self, sessionID:usize) {
+        match self.sessionNames.remove(&sessionID) {
+            Some( name ) => {self.names.remove(&name);},
+            None => {},
+        }
+    }
+}
+
+fn getUserID(name:&str) -> usize {
+    let mut hash:u32=0x811C_9DC5;
+
+    for byte in name.as_bytes() {
+        hash^=*byte as u32;
+        hash=hash.wrapping_mul(0x0100_0193);
+    }
+
+    LAN_USER_ID_BEGIN + (hash & LAN_USER_ID_MASK) as usize
+}
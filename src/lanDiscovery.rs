@@ -0,0 +1,174 @@
+use std::sync::Arc;
+
+use std::io::ErrorKind;
+
+use mio::*;
+use mio::udp::UdpSocket;
+use std::net::{SocketAddr, SocketAddrV4, Ipv4Addr};
+
+use std::collections::HashMap;
+
+use time::get_time;
+
+use appData::AppData;
+use server::Server;
+use rateLimiter::{IPRateLimiters, takeIPRateLimit, removeFullRateLimiters};
+
+use packet::{ClientToServerUDPPacket, LANServerAnnouncement, LAN_DISCOVERY_QUERY_MAGIC, LAN_DISCOVERY_MAGIC, SERVER_QUERY_REQUEST_LENGTH};
+
+/*
+В редакторе и в режиме LAN клиенты находят сервер без master server-а.
+Сервер раз в lan.discoveryInterval секунд отправляет LANServerAnnouncement на lan.discoveryAddress:lan.discoveryPort(broadcast или multicast группа),
+а клиент, который только что запустился, может сам отправить запрос на этот порт и сразу получить ответ.
+Запрос устроен как запрос UDPServer-а(см. SERVER_QUERY_MAGIC), но с LAN_DISCOVERY_QUERY_MAGIC, ответы ограничены тем же rateLimit.udpQuery.
+*/
+
+const LAN_DISCOVERY_DATAGRAM_LENGTH_LIMIT:usize = 256;
+
+pub struct LANDiscovery{
+    socket:UdpSocket,
+    pub token:Token,
+    beaconAddr:SocketAddr,
+    nextBeaconTime:i64,
+    readBuffer:Vec<u8>,
+    rateLimiters:IPRateLimiters,
+}
+
+impl LANDiscovery{
+    ///Returns None, if discovery is disabled or server works neither as editor nor in LAN mode
+    pub fn new(appData:&Arc<AppData>) -> Result<Option<LANDiscovery>, String> {
+        let serverConfig=&appData.serverConfig;
+
+        if !serverConfig.lan_discoveryEnabled || !(appData.isEditor || serverConfig.lan_enabled) {
+            return Ok(None);
+        }
+
+        //broadcast-ы приходят только на сокет, привязанный к 0.0.0.0
+        let socketAddr=SocketAddr::V4( SocketAddrV4::new(Ipv4Addr::new(0,0,0,0), serverConfig.lan_discoveryPort) );
+
+        let socket=match UdpSocket::bind(&socketAddr) {
+            Ok ( socket ) => socket,
+            Err( e ) => return Err( format!("LAN discovery socket error : {:?}", e) ),
+        };
+
+        let address=serverConfig.lan_discoveryAddress;
+
+        if address.is_multicast() {
+            try!(socket.join_multicast_v4(&address, &serverConfig.lan_discoveryInterface)
+                .or_else(|e| Err(format!("Can not join multicast group {} : {:?}", address, e))));
+        }else{
+            try!(socket.set_broadcast(true)
+                .or_else(|e| Err(format!("Can not allow broadcast for LAN discovery : {:?}", e))));
+        }
+
+        Ok(Some(
+            LANDiscovery{
+                socket:socket,
+                token:Token(20_000_002),
+                beaconAddr:SocketAddr::V4( SocketAddrV4::new(address, serverConfig.lan_discoveryPort) ),
+                nextBeaconTime:0,
+                readBuffer:vec![0; LAN_DISCOVERY_DATAGRAM_LENGTH_LIMIT],
+                rateLimiters:HashMap::new(),
+            }
+        ))
+    }
+
+    pub fn register(&mut self, poll:&mut Poll) -> Result<(), &'static str> {
+        poll.register(
+            &self.socket,
+            self.token,
+            Ready::readable(),
+            PollOpt::edge()
+        ).or_else(|e|
+            Err("Can not register LAN discovery socket")
+        )
+    }
+
+    pub fn deregister(&mut self, poll:&mut Poll) {
+        poll.deregister(&self.socket);
+    }
+
+    ///Answers to queries. Beacons of other servers come here too, they are ignored
+    pub fn processEvent(&mut self, appData:&Arc<AppData>, server:&Server) -> Result<(), &'static str> {
+        loop{ //edge - we should read all datagrams
+            let (length, clientAddr)=match self.socket.recv_from( &mut self.readBuffer[..] ) {
+                Ok ( None ) => return Ok(()),
+                Ok ( Some(( length, clientAddr )) ) => (length, clientAddr),
+                Err( e ) => {
+                    if e.kind()==ErrorKind::WouldBlock {
+                        return Ok(());
+                    }
+
+                    return Err( "LAN discovery socket read error" );
+                },
+            };
+
+            //ответ не длиннее запроса больше, чем в 4 раза, как и у UDPServer
+            if length<SERVER_QUERY_REQUEST_LENGTH || ClientToServerUDPPacket::unpackSession(&self.readBuffer)!=LAN_DISCOVERY_QUERY_MAGIC {
+                continue;
+            }
+
+            if !takeIPRateLimit(&appData.log, &mut self.rateLimiters, &appData.serverConfig.rateLimit_udpQuery, clientAddr.ip(), length, "LAN discovery queries") {
+                continue;
+            }
+
+            let clientTime=ClientToServerUDPPacket::unpackTime(&self.readBuffer);
+
+            match getAnnouncement(appData, server).pack(LAN_DISCOVERY_MAGIC, clientTime) {
+                Ok ( datagram ) => {self.socket.send_to(&datagram[..], &clientAddr);},
+                Err( e ) => appData.log.print( format!("[ERROR] LAN discovery answer : {}", e) ),
+            }
+        }
+    }
+
+    pub fn processTick(&mut self, appData:&Arc<AppData>, server:&Server) {
+        let interval=appData.serverConfig.lan_discoveryInterval as i64;
+
+        if interval==0 {
+            return;
+        }
+
+        let time=get_time().sec;
+
+        if time<self.nextBeaconTime {
+            return;
+        }
+
+        self.nextBeaconTime=time+interval;
+
+        removeFullRateLimiters(&mut self.rateLimiters);
+
+        match getAnnouncement(appData, server).pack(LAN_DISCOVERY_MAGIC, 0) {
+            Ok ( datagram ) => {
+                match self.socket.send_to(&datagram[..], &self.beaconAddr) {
+                    Ok ( _ ) => {},
+                    Err( e ) => appData.log.print( format!("[WARNING] LAN discovery beacon to {} : {:?}", self.beaconAddr, e) ),
+                }
+            },
+            Err( e ) => appData.log.print( format!("[ERROR] LAN discovery beacon : {}", e) ),
+        }
+    }
+}
+
+fn getAnnouncement(appData:&Arc<AppData>, server:&Server) -> LANServerAnnouncement {
+    let serverConfig=&appData.serverConfig;
+
+    //если сервер слушает все интерфейсы, клиенту известен только адрес, откуда пришла датаграмма
+    let address=if serverConfig.server_address!="0.0.0.0" {
+        serverConfig.server_address.clone()
+    }else if !serverConfig.lan_discoveryInterface.is_unspecified() {
+        format!("{}", serverConfig.lan_discoveryInterface)
+    }else{
+        String::new()
+    };
+
+    LANServerAnnouncement{
+        address:address,
+        gamePort:serverConfig.server_gamePort,
+        editorPort:serverConfig.server_editorPort,
+        isEditor:appData.isEditor,
+        name:serverConfig.server_name.clone(),
+        playersCount:*server.playersCount.read().unwrap(),
+        playersLimit:*server.playersLimit.read().unwrap(),
+    }
+}
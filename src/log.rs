@@ -9,8 +9,46 @@ use std::error::Error;
 
 //use webInterface::WebInterface;
 
+///Messages with level above current one are not written. Level of message is taken from its prefix like [INFO]
+#[derive(PartialEq, Eq, PartialOrd, Ord, Copy, Clone)]
+pub enum LogLevel{
+    Error,
+    Warning,
+    Info,
+}
+
+impl LogLevel{
+    pub fn parse(name:&str) -> Result<LogLevel, String> {
+        match name {
+            "error" => Ok( LogLevel::Error ),
+            "warning" => Ok( LogLevel::Warning ),
+            "info" => Ok( LogLevel::Info ),
+            _ => Err( format!("Unknown log level \"{}\", expected error, warning or info", name) ),
+        }
+    }
+
+    pub fn print(&self) -> &'static str {
+        match *self {
+            LogLevel::Error => "error",
+            LogLevel::Warning => "warning",
+            LogLevel::Info => "info",
+        }
+    }
+
+    fn ofMessage(text:&str) -> LogLevel {
+        if text.starts_with("[ERROR]") {
+            LogLevel::Error
+        }else if text.starts_with("[WARNING]") {
+            LogLevel::Warning
+        }else{
+            LogLevel::Info
+        }
+    }
+}
+
 pub struct Log{
     pub logFile:Mutex<File>,
+    level:RwLock<LogLevel>,
     //adminServer
 }
 
@@ -34,6 +72,7 @@ impl Log{
 
         Ok(Log{
             logFile:Mutex::new(file),
+            level:RwLock::new(LogLevel::Info),
             //webInterface:RwLock::new( None ),
         })
     }
@@ -44,7 +83,19 @@ impl Log{
         logFile.write("\n".as_bytes());
     }
 
+    pub fn setLevel(&self, level:LogLevel) {
+        *self.level.write().unwrap()=level;
+    }
+
+    pub fn getLevel(&self) -> LogLevel {
+        *self.level.read().unwrap()
+    }
+
     pub fn print(&self, text:String){
+        if LogLevel::ofMessage(&text) > self.getLevel() {
+            return;
+        }
+
         {
             let mut logFile=self.logFile.lock().unwrap();
             logFile.write_all(text.as_bytes());
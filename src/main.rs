@@ -72,6 +72,7 @@ extern crate byteorder;
 extern crate rustc_serialize;
 extern crate bincode;
 extern crate rand;
+extern crate crypto;
 
 use std::env;
 use std::thread;
@@ -79,7 +80,7 @@ use std::sync::{Mutex,RwLock,Arc,Barrier,Weak};
 
 mod log;
 mod appData;
-//mod adminServer;
+mod adminServer;
 mod lexer;
 mod description;
 mod serverConfig;
@@ -89,24 +90,37 @@ mod modLoader;
 mod gameState;
 mod map;
 mod storage;
+mod accounts;
+mod lanAuth;
+mod lanDiscovery;
+mod banList;
+mod roles;
+mod worldStore;
 mod server;
 mod tcpServer;
 mod tcpConnection;
 mod udpServer;
 mod udpConnection;
 mod player;
+mod game;
 mod packet;
 mod httpRequester;
+mod masterServer;
+mod rateLimiter;
+mod command;
+mod console;
 
 
 use appData::AppData;
 use log::Log;
 use serverConfig::ServerConfig;
 use gameState::GameState;
-//use adminServer::AdminServer;
+use adminServer::AdminServer;
 use storage::Storage;
 use httpRequester::HTTPRequester;
-use server::Server;
+use masterServer::MasterServerRegistration;
+use server::{Server, ServerState};
+use console::Console;
 
 
 
@@ -154,13 +168,23 @@ fn main() {
     //===================AppData======================
     let appData=AppData::initialize(serverConfig, log, isEditor);
 
-    //AdminServer
+    //===================AdminServer==================
+
+    appData.log.print(format!("[INFO] Connecting admin server"));
+
+    match AdminServer::connect( appData.clone() ) {
+        Ok ( _ ) => appData.log.print(format!("[INFO] Connected to admin server")),
+        Err( e ) => {
+            appData.log.print(format!("[ERROR] Can not connect to admin server : {}", e));
+            return;
+        }
+    }
 
     //===================Storage======================
 
     if !Storage::initialize (appData.clone()) {
         *appData.gameState.write().unwrap()=GameState::Error;
-        //close adminServer
+        AppData::destroy( appData );
         return;
     }
 
@@ -186,6 +210,14 @@ fn main() {
         }
     }
 
+    //===================Console=======================
+
+    Console::start( appData.clone() );
+
+    //===================Master servers================
+
+    let masterServerRegistration=MasterServerRegistration::new(&appData);
+
     /*
     appData.getHTTPRequesterAnd(|httpRequester| httpRequester.addRequest(
         "89.110.48.1:1941",
@@ -230,7 +262,31 @@ fn main() {
     ));
     */
 
-    thread::sleep_ms(20000);
+    //сервер работает, пока server_admin не пришлет cmd:stop или не упадет один из потоков сервера
+    while !{*appData.shouldStop.read().unwrap()} {
+        thread::sleep_ms(100);
+
+        let isProcessing=match *appData.server.read().unwrap() {
+            Some( ref server ) => *server.state.read().unwrap()==ServerState::Processing,
+            None => false,
+        };
+
+        if !isProcessing {
+            appData.log.print(String::from("[ERROR] Server has been stopped by error"));
+            break;
+        }
+
+        match masterServerRegistration {
+            Some( ref registration ) => registration.processTick(&appData),
+            None => {},
+        }
+    }
+
+    //до остановки HTTPRequester, чтобы master server сразу убрал сервер из списка
+    match masterServerRegistration {
+        Some( ref registration ) => registration.deregister(&appData),
+        None => {},
+    }
 
     AppData::destroy( appData );
 
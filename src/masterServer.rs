@@ -0,0 +1,347 @@
+use std::sync::{Mutex, Arc};
+use std::thread;
+
+use std::collections::HashMap;
+
+use time::get_time;
+
+use appData::AppData;
+use serverConfig::ServerConfig;
+use version::{Version, GAME_VERSION};
+use modLoader::readActiveMods;
+
+/*
+Чтобы master server показывал сервер в списке, сервер регистрируется в каждом репозитории(POST /registerServer),
+затем раз в masterServer.heartbeatInterval отправляет POST /heartbeat, а при остановке POST /deregisterServer.
+Тело запроса - application/x-www-form-urlencoded: address, port, name, players, playersLimit, map, version, mods(через запятую).
+Если master server отвечает ошибкой или не отвечает, следующий запрос откладывается вдвое дольше, но не больше masterServer.heartbeatMaxBackoff.
+Если на heartbeat он отвечает 404 или "Error:", значит он нас забыл(например, был перезапущен), и мы регистрируемся снова.
+*/
+
+const REGISTRATION_REQUEST_TIMEOUT: usize = 10; //sec
+const DEREGISTRATION_TIMEOUT: i64 = 3; //sec, how long server waits for answers to deregistration before stop
+
+struct Endpoint{
+    address:String,
+    deadUntil:i64, //sec, endpoint is skipped until this time
+}
+
+///Addresses of master servers(repositories from serverConfig.cfg) in order of priority, and their health
+pub struct MasterServers{
+    endpoints:Mutex<Vec<Endpoint>>,
+    cooldown:i64,
+}
+
+impl MasterServers{
+    pub fn new(serverConfig:&ServerConfig) -> MasterServers {
+        let mut endpoints=Vec::new();
+
+        for address in serverConfig.repositories.read().unwrap().iter() {
+            endpoints.push(
+                Endpoint{
+                    address:address.clone(),
+                    deadUntil:0,
+                }
+            );
+        }
+
+        MasterServers{
+            endpoints:Mutex::new(endpoints),
+            cooldown:serverConfig.masterServer_cooldown as i64,
+        }
+    }
+
+    ///Returns index and address of the first alive endpoint, beginning with fromIndex
+    pub fn selectEndpoint(&self, fromIndex:usize) -> Option<(usize, String)> {
+        let endpoints=self.endpoints.lock().unwrap();
+        let time=get_time().sec;
+
+        for index in fromIndex..endpoints.len() {
+            if endpoints[index].deadUntil<=time {
+                return Some( (index, endpoints[index].address.clone()) );
+            }
+        }
+
+        None
+    }
+
+    ///Takes new list of repositories, health of remaining addresses is kept
+    pub fn reload(&self, repositories:&Vec<String>) {
+        let mut endpoints=self.endpoints.lock().unwrap();
+        let mut newEndpoints=Vec::new();
+
+        for address in repositories.iter() {
+            let deadUntil=match endpoints.iter().find(|endpoint| endpoint.address==*address) {
+                Some( endpoint ) => endpoint.deadUntil,
+                None => 0,
+            };
+
+            newEndpoints.push(
+                Endpoint{
+                    address:address.clone(),
+                    deadUntil:deadUntil,
+                }
+            );
+        }
+
+        *endpoints=newEndpoints;
+    }
+
+    //по адресу, а не по индексу: пока ждали ответ, список мог быть перечитан(reload) и индексы сдвинулись
+    pub fn markDead(&self, address:&str) {
+        match self.endpoints.lock().unwrap().iter_mut().find(|endpoint| endpoint.address==address) {
+            Some( endpoint ) => endpoint.deadUntil=get_time().sec+self.cooldown,
+            None => {},
+        }
+    }
+
+    pub fn markAlive(&self, address:&str) {
+        match self.endpoints.lock().unwrap().iter_mut().find(|endpoint| endpoint.address==address) {
+            Some( endpoint ) => endpoint.deadUntil=0,
+            None => {},
+        }
+    }
+}
+
+struct Registration{
+    isRegistered:bool,
+    nextRequestTime:i64, //sec
+    waitsAnswerUntil:Option<i64>, //HTTPRequester does not call callback, if request has failed or timed out
+    failures:u32,
+}
+
+///Registration of the server in repositories, it is processed by main thread
+pub struct MasterServerRegistration{
+    registrations:Arc<Mutex<HashMap<String, Registration>>>, //address of repository
+    mods:String,
+}
+
+impl Registration{
+    fn fail(&mut self, appData:&AppData, address:&str, time:i64, error:String) {
+        let serverConfig=&appData.serverConfig;
+
+        self.failures+=1;
+
+        let backoff=serverConfig.masterServer_heartbeatInterval.saturating_mul( 1 << (if self.failures>10 { 10 } else { self.failures }) );
+        let backoff=if backoff>serverConfig.masterServer_heartbeatMaxBackoff { serverConfig.masterServer_heartbeatMaxBackoff } else { backoff };
+
+        self.nextRequestTime=time+backoff as i64;
+
+        appData.log.print( format!("[WARNING] Master server {} : {}, next try in {} sec", address, error, backoff) );
+    }
+}
+
+impl MasterServerRegistration{
+    ///Returns None in editor and LAN mode, master servers do not list such servers
+    pub fn new(appData:&Arc<AppData>) -> Option<MasterServerRegistration> {
+        if appData.isEditor || appData.serverConfig.lan_enabled {
+            return None;
+        }
+
+        let mods=match readActiveMods() {
+            Ok ( mods ) => mods.join(","),
+            Err( e ) => {
+                appData.log.print( format!("[WARNING] Master server registration : {}", e) );
+                String::new()
+            },
+        };
+
+        Some(
+            MasterServerRegistration{
+                registrations:Arc::new(Mutex::new(HashMap::new())),
+                mods:mods,
+            }
+        )
+    }
+
+    ///Sends registration or heartbeat to repositories, whose time has come. Repositories are taken from serverConfig, so they may be reloaded
+    pub fn processTick(&self, appData:&Arc<AppData>) {
+        let time=get_time().sec;
+        let repositories=appData.serverConfig.repositories.read().unwrap().clone();
+
+        let mut requests=Vec::new();
+
+        {
+            let mut registrations=self.registrations.lock().unwrap();
+
+            let removedAddresses:Vec<String>=registrations.keys().filter(|address| !repositories.contains(address)).cloned().collect();
+
+            for address in removedAddresses {
+                registrations.remove(&address);
+            }
+
+            for address in repositories.iter() {
+                let registration=registrations.entry(address.clone()).or_insert_with(||
+                    Registration{
+                        isRegistered:false,
+                        nextRequestTime:0,
+                        waitsAnswerUntil:None,
+                        failures:0,
+                    }
+                );
+
+                match registration.waitsAnswerUntil {
+                    Some( waitsAnswerUntil ) => {
+                        if time<waitsAnswerUntil {
+                            continue;
+                        }
+
+                        registration.waitsAnswerUntil=None;
+                        registration.fail(appData, address, time, String::from("no answer"));
+                    },
+                    None => {},
+                }
+
+                if time<registration.nextRequestTime {
+                    continue;
+                }
+
+                registration.waitsAnswerUntil=Some(time + REGISTRATION_REQUEST_TIMEOUT as i64 + 1);
+
+                let method=if registration.isRegistered { "heartbeat" } else { "registerServer" };
+                requests.push( (address.clone(), method) );
+            }
+        }
+
+        for (address, method) in requests {
+            self.sendRequest(appData, address, method);
+        }
+    }
+
+    ///Sends deregistration to repositories, where the server is registered, and waits for answers not longer than DEREGISTRATION_TIMEOUT
+    pub fn deregister(&self, appData:&Arc<AppData>) {
+        let time=get_time().sec;
+        let mut addresses=Vec::new();
+
+        {
+            let mut registrations=self.registrations.lock().unwrap();
+
+            for (address, registration) in registrations.iter_mut() {
+                if registration.isRegistered {
+                    registration.waitsAnswerUntil=Some(time+DEREGISTRATION_TIMEOUT);
+                    addresses.push(address.clone());
+                }else{
+                    registration.waitsAnswerUntil=None;
+                }
+            }
+        }
+
+        if addresses.len()==0 {
+            return;
+        }
+
+        appData.log.print( String::from("[INFO] Deregistering server at master servers") );
+
+        for address in addresses {
+            self.sendRequest(appData, address, "deregisterServer");
+        }
+
+        while get_time().sec<time+DEREGISTRATION_TIMEOUT {
+            if self.registrations.lock().unwrap().values().all(|registration| registration.waitsAnswerUntil.is_none()) {
+                break;
+            }
+
+            thread::sleep_ms(100);
+        }
+    }
+
+    fn sendRequest(&self, appData:&Arc<AppData>, address:String, method:&'static str) {
+        let body=self.makeRequestBody(appData);
+
+        let request=format!("POST /{} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/x-www-form-urlencoded\r\nContent-Length: {}\r\n\r\n{}",
+            method, address, body.len(), body
+        ).into_bytes();
+
+        let registrations=self.registrations.clone();
+        let callbackAppData=appData.clone();
+        let callbackAddress=address.clone();
+
+        //HTTPRequester может быть уже разрушен из-за ошибки, тогда ждем waitsAnswerUntil, как при отсутствии ответа
+        match *appData.httpRequester.read().unwrap() {
+            Some( ref httpRequester ) => httpRequester.addRequest(
+                &address,
+                request,
+                REGISTRATION_REQUEST_TIMEOUT,
+
+                move |responseCode:usize, buffer:&[u8] | {
+                    //вызывается потоком HTTPRequester
+                    let appData=&callbackAppData;
+                    let address=&callbackAddress;
+                    let time=get_time().sec;
+
+                    let mut registrations=registrations.lock().unwrap();
+
+                    let registration=match registrations.get_mut(address) {
+                        Some( registration ) => registration,
+                        None => return,
+                    };
+
+                    registration.waitsAnswerUntil=None;
+
+                    if method=="deregisterServer" {
+                        registration.isRegistered=false;
+                        return;
+                    }
+
+                    let response=String::from_utf8_lossy(buffer).into_owned();
+
+                    if responseCode==200 && !response.starts_with("Error:") {
+                        if !registration.isRegistered {
+                            appData.log.print( format!("[INFO] Server has been registered at master server {}", address) );
+                        }
+
+                        registration.isRegistered=true;
+                        registration.failures=0;
+                        registration.nextRequestTime=time + appData.serverConfig.masterServer_heartbeatInterval as i64;
+                    }else{
+                        if responseCode==404 || response.starts_with("Error:") {
+                            registration.isRegistered=false;
+                        }
+
+                        let error=if response.starts_with("Error:") {
+                            response
+                        }else{
+                            format!("{} answered with code {}", method, responseCode)
+                        };
+
+                        registration.fail(appData, address, time, error);
+                    }
+                }
+            ),
+            None => {},
+        }
+    }
+
+    fn makeRequestBody(&self, appData:&AppData) -> String {
+        let serverConfig=&appData.serverConfig;
+
+        let (playersCount, playersLimit)=match *appData.server.read().unwrap() {
+            Some( ref server ) => (*server.playersCount.read().unwrap(), *server.playersLimit.read().unwrap()),
+            None => (0, serverConfig.server_playersLimit),
+        };
+
+        format!("address={}&port={}&name={}&players={}&playersLimit={}&map={}&version={}&mods={}",
+            encodeURLComponent(&serverConfig.server_address),
+            serverConfig.server_gamePort,
+            encodeURLComponent(&serverConfig.server_name),
+            playersCount,
+            playersLimit,
+            encodeURLComponent(&serverConfig.getMapDescription()),
+            Version::fromBytes(GAME_VERSION).print(),
+            encodeURLComponent(&self.mods)
+        )
+    }
+}
+
+fn encodeURLComponent(text:&str) -> String {
+    let mut encoded=String::with_capacity(text.len());
+
+    for byte in text.bytes() {
+        match byte {
+            b'A'...b'Z' | b'a'...b'z' | b'0'...b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(byte as char),
+            _ => encoded.push_str( &format!("%{:02X}", byte) ),
+        }
+    }
+
+    encoded
+}
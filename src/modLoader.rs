@@ -249,33 +249,12 @@ fn selectModulesToLoad( appData:&Arc<AppData> ) -> Result< Vec<String>, String >
 
     //========================Read ActivateMods list=====================
 
-    let activeModsFileName="activeMods.list";
-
-    let mut file=match File::open(activeModsFileName) {
-        Ok( f ) => f,
-        Err( e ) => return Err(format!("Can not read file \"{}\" : {}", activeModsFileName, e.description())),
-    };
+    let mut activateMods:VecDeque< (String, Option<Version>) >=VecDeque::new();
 
-    let mut content = String::new();
-    match file.read_to_string(&mut content){
-        Ok( c )  => {},
-        Err( e ) => return Err(format!("Can not read file \"{}\" : {}", activeModsFileName, e.description())),
+    for modName in try!(readActiveMods()) {
+        activateMods.push_front( (modName, None) );
     }
 
-    let mut activateMods:VecDeque< (String, Option<Version>) >=match description::parse( &content, |root| {
-        let activeModsList=try!( root.getList("active mods") );
-        let mut activateMods:VecDeque< (String, Option<Version>) >=VecDeque::new();
-
-        for mname in activeModsList.iter() {
-            activateMods.push_front( (try!(mname.getString()).clone(), None) );
-        }
-
-        Ok(activateMods)
-    }){
-        Ok( am ) => am,
-        Err( msg ) => return Err(format!("Can not decode file \"{}\" : {}", activeModsFileName, msg)),
-    };
-
     //=======================Check And Activate Mods===================
 
     let mut activatedMods=Vec::new();
@@ -315,3 +294,33 @@ pub fn loadMods( appData:Arc<AppData> ) -> Result< (), String >{
     appData.log.write("Loading mods");
     Ok(())
 }
+
+///Names of mods from activeMods.list
+pub fn readActiveMods() -> Result< Vec<String>, String >{
+    let activeModsFileName="activeMods.list";
+
+    let mut file=match File::open(activeModsFileName) {
+        Ok( f ) => f,
+        Err( e ) => return Err(format!("Can not read file \"{}\" : {}", activeModsFileName, e.description())),
+    };
+
+    let mut content = String::new();
+    match file.read_to_string(&mut content){
+        Ok( c )  => {},
+        Err( e ) => return Err(format!("Can not read file \"{}\" : {}", activeModsFileName, e.description())),
+    }
+
+    match description::parse( &content, |root| {
+        let activeModsList=try!( root.getList("active mods") );
+        let mut activeMods=Vec::new();
+
+        for mname in activeModsList.iter() {
+            activeMods.push( try!(mname.getString()).clone() );
+        }
+
+        Ok(activeMods)
+    }){
+        Ok( am ) => Ok(am),
+        Err( msg ) => Err(format!("Can not decode file \"{}\" : {}", activeModsFileName, msg)),
+    }
+}
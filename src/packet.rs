@@ -1,25 +1,142 @@
-use bincode::rustc_serialize::{encode_into, decode};
+use bincode::rustc_serialize::{encode_into, decode, encoded_size};
+use rustc_serialize::{Encodable, Encoder, Decodable, Decoder};
 use bincode::SizeLimit;
 use byteorder::{ByteOrder, BigEndian};
 
+use server::getServerTime;
+use version::Version;
+
 const MESSAGE_TO_SERVER_LIMIT: u64 = 16*1024;
 const MESSAGE_TO_CLIENT_LIMIT: u64 = 64*1024;
 
+//session(8) time(8) and reliability part of header(12), see UDPConnection
+pub const UDP_PACKET_HEADER_LENGTH: usize = 28;
+
+///Reason of disconnection, sent with ServerToClientTCPPacket::Disconnected and ServerToClientUDPPacket::Disconnected.
+///Numbers must never change, client localises message and decides, whether to reconnect automatically, by them
+#[derive(PartialEq, Eq, Copy, Clone)]
+pub enum DisconnectionCode{
+    Unknown,           //0, code of newer server
+    ServerShutdown,    //1, client may reconnect later
+    ServerError,       //2, internal error of server
+    ProtocolError,     //3, client has sent unexpected or broken packet
+    Timeout,           //4, client has not answered in time, may reconnect
+    VersionMismatch,   //5, client must be updated, do not reconnect
+    AuthFailed,        //6, wrong login or password
+    InvalidSession,    //7, sessionID is rejected by master server, client should get new one
+    ServerFull,        //8, may reconnect later
+    Kicked,            //9
+    Banned,            //10, do not reconnect
+    RateLimitExceeded, //11
+    NameUnavailable,   //12, name of LAN player is used or reserved
+    SessionExpired,    //13, session has not been resumed in time
+}
+
+impl DisconnectionCode{
+    pub fn toNumber(&self) -> u16 {
+        match *self {
+            DisconnectionCode::Unknown => 0,
+            DisconnectionCode::ServerShutdown => 1,
+            DisconnectionCode::ServerError => 2,
+            DisconnectionCode::ProtocolError => 3,
+            DisconnectionCode::Timeout => 4,
+            DisconnectionCode::VersionMismatch => 5,
+            DisconnectionCode::AuthFailed => 6,
+            DisconnectionCode::InvalidSession => 7,
+            DisconnectionCode::ServerFull => 8,
+            DisconnectionCode::Kicked => 9,
+            DisconnectionCode::Banned => 10,
+            DisconnectionCode::RateLimitExceeded => 11,
+            DisconnectionCode::NameUnavailable => 12,
+            DisconnectionCode::SessionExpired => 13,
+        }
+    }
+
+    pub fn fromNumber(number:u16) -> DisconnectionCode {
+        match number {
+            1 => DisconnectionCode::ServerShutdown,
+            2 => DisconnectionCode::ServerError,
+            3 => DisconnectionCode::ProtocolError,
+            4 => DisconnectionCode::Timeout,
+            5 => DisconnectionCode::VersionMismatch,
+            6 => DisconnectionCode::AuthFailed,
+            7 => DisconnectionCode::InvalidSession,
+            8 => DisconnectionCode::ServerFull,
+            9 => DisconnectionCode::Kicked,
+            10 => DisconnectionCode::Banned,
+            11 => DisconnectionCode::RateLimitExceeded,
+            12 => DisconnectionCode::NameUnavailable,
+            13 => DisconnectionCode::SessionExpired,
+            _ => DisconnectionCode::Unknown,
+        }
+    }
+
+    pub fn print(&self) -> &'static str {
+        match *self {
+            DisconnectionCode::Unknown => "unknown",
+            DisconnectionCode::ServerShutdown => "server shutdown",
+            DisconnectionCode::ServerError => "server error",
+            DisconnectionCode::ProtocolError => "protocol error",
+            DisconnectionCode::Timeout => "timeout",
+            DisconnectionCode::VersionMismatch => "version mismatch",
+            DisconnectionCode::AuthFailed => "auth failed",
+            DisconnectionCode::InvalidSession => "invalid session",
+            DisconnectionCode::ServerFull => "server full",
+            DisconnectionCode::Kicked => "kicked",
+            DisconnectionCode::Banned => "banned",
+            DisconnectionCode::RateLimitExceeded => "rate limit exceeded",
+            DisconnectionCode::NameUnavailable => "name unavailable",
+            DisconnectionCode::SessionExpired => "session expired",
+        }
+    }
+}
+
+//кодируем числом, а не индексом варианта, чтобы порядок в enum ни на что не влиял
+impl Encodable for DisconnectionCode {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        self.toNumber().encode(s)
+    }
+}
+
+impl Decodable for DisconnectionCode {
+    fn decode<D: Decoder>(d: &mut D) -> Result<DisconnectionCode, D::Error> {
+        let number=try!(u16::decode(d));
+
+        Ok(DisconnectionCode::fromNumber(number))
+    }
+}
+
+//Versions и IncompatibleVersions всегда первые, а Disconnected второй: клиент и сервер разных версий должны понять их,
+//остальные пакеты могут меняться, но тогда увеличивается PROTOCOL_VERSION
 #[derive(RustcEncodable, RustcDecodable)]
 pub enum ClientToServerTCPPacket{
+    Versions( Version, Version ), //protocol, game - the first packet of client, its index must never change
+
     ClientError( String ),
     ClientDesire( String ),
 
     SessionID( String ),
+    Login( String, String ), //userName, password of local account, answer to ServerToClientTCPPacket::LoginOrRegister
+    Register( String, String ),
+    LANName( String ), //instead of SessionID, only in LAN mode
+    Resume( usize, u64 ), //instead of SessionID, sessionID and resume token of dropped connection
+    Command( String ), //command of player from chat, like "kick Griefer spam", answer is ServerToClientTCPPacket::CommandResult
 }
 
 impl ClientToServerTCPPacket{
     pub fn pack(&self) -> Vec<u8>{
         let bufferLength=match *self{
+            ClientToServerTCPPacket::Versions ( _, _ ) => 16,
+
             ClientToServerTCPPacket::ClientError( _ ) => 64,
             ClientToServerTCPPacket::ClientDesire( _ ) => 64,
 
             ClientToServerTCPPacket::SessionID ( _ ) => 64,
+            ClientToServerTCPPacket::Login ( _, _ ) => 128,
+            ClientToServerTCPPacket::Register ( _, _ ) => 128,
+            ClientToServerTCPPacket::LANName ( _ ) => 64,
+            ClientToServerTCPPacket::Resume ( _, _ ) => 32,
+            ClientToServerTCPPacket::Command ( _ ) => 64,
         };
 
         let mut buffer:Vec<u8>=Vec::with_capacity(bufferLength);
@@ -53,23 +170,37 @@ impl ClientToServerTCPPacket{
 
 #[derive(RustcEncodable, RustcDecodable)]
 pub enum ServerToClientTCPPacket{
-    ServerShutdown,
-    ServerError( String ),
-    ServerDesire( String ),
+    IncompatibleVersions( Version, Version ), //required protocol and game versions, connection is closed after it, its index must never change
+    Disconnected( DisconnectionCode, String ), //code, detail(may be empty), connection is closed after it, its index must never change
 
     LoginOrRegister,
+    LoginOrRegisterFailed( String, usize ), //reason, remaining attempts
     InitializeUDPConnection( usize ),
+    UDPConnectionInitialized( u64, u64 ), //session(the same as in ServerToClientUDPPacket::InitializationAccepted), resume token
+    SessionResumed( u64 ), //new resume token, UDP connection and player are the same
+    Notice( String ), //text of operator for all players
+    ShutdownWarning( u64 ), //seconds before shutdown
+    ShutdownCancelled,
+    QueuePosition( usize, usize ), //position(from 1) and length of queue, server is full and client waits for a free slot
+    CommandResult( bool, String ), //is successful, answer or error
 }
 
 impl ServerToClientTCPPacket{
     pub fn pack(&self) -> Vec<u8>{
         let bufferLength=match *self{
-            ServerToClientTCPPacket::ServerShutdown => 16,
-            ServerToClientTCPPacket::ServerError( _ ) => 64,
-            ServerToClientTCPPacket::ServerDesire( _ ) => 64,
+            ServerToClientTCPPacket::IncompatibleVersions( _, _ ) => 16,
+            ServerToClientTCPPacket::Disconnected( _, _ ) => 64,
 
             ServerToClientTCPPacket::LoginOrRegister => 16,
+            ServerToClientTCPPacket::LoginOrRegisterFailed( _, _ ) => 64,
             ServerToClientTCPPacket::InitializeUDPConnection ( _ ) => 16,
+            ServerToClientTCPPacket::UDPConnectionInitialized ( _, _ ) => 24,
+            ServerToClientTCPPacket::SessionResumed ( _ ) => 16,
+            ServerToClientTCPPacket::Notice ( _ ) => 128,
+            ServerToClientTCPPacket::ShutdownWarning ( _ ) => 16,
+            ServerToClientTCPPacket::ShutdownCancelled => 16,
+            ServerToClientTCPPacket::QueuePosition ( _, _ ) => 24,
+            ServerToClientTCPPacket::CommandResult ( _, _ ) => 128,
         };
 
         let mut buffer:Vec<u8>=Vec::with_capacity(bufferLength);
@@ -99,26 +230,30 @@ impl ServerToClientTCPPacket{
 #[derive(RustcEncodable, RustcDecodable)]
 pub enum ClientToServerUDPPacket{
     Initialization(usize),
+    Pong, //answer to ServerToClientUDPPacket::Ping
+    ClockSyncResponse( u64, u64 ), //time of server from ClockSyncRequest as is, time of client, when it has been received
 }
 
 impl ClientToServerUDPPacket{
     pub fn pack(&self) -> Result< Vec<u8>, String>{
         let bufferLength=match *self{
-            ClientToServerUDPPacket::Initialization( _ ) => 32,
+            ClientToServerUDPPacket::Initialization( _ ) => 48,
+            ClientToServerUDPPacket::Pong => 32,
+            ClientToServerUDPPacket::ClockSyncResponse( _, _ ) => 48,
         };
 
         let mut buffer:Vec<u8>=Vec::with_capacity(bufferLength);
 
-        unsafe { buffer.set_len(16); }
+        buffer.resize(UDP_PACKET_HEADER_LENGTH, 0);
 
-        match encode_into(self, &mut buffer, SizeLimit::Bounded(MESSAGE_TO_CLIENT_LIMIT-16) ){
+        match encode_into(self, &mut buffer, SizeLimit::Bounded(MESSAGE_TO_CLIENT_LIMIT-UDP_PACKET_HEADER_LENGTH as u64) ){
             Ok ( _ ) =>Ok(buffer),
             Err( e ) =>Err( format!("Packet serialization error : {:?}, (maybe it's length is more than {}?)", e, MESSAGE_TO_CLIENT_LIMIT) ),
         }
     }
 
     pub fn unpack(message:&Vec<u8>) -> Result<ClientToServerUDPPacket, &'static str>{
-        match decode(&message[16..]){
+        match decode(&message[UDP_PACKET_HEADER_LENGTH..]){
             Ok ( p ) => Ok ( p ),
             Err( e ) => Err("deserialization error"),
         }
@@ -140,50 +275,159 @@ pub enum ClientToServerUDPPacketAcception{
 
 #[derive(RustcEncodable, RustcDecodable)]
 pub enum ServerToClientUDPPacket{
-}
-
-/*
-
-#[derive(RustcEncodable, RustcDecodable)]
-pub enum ServerToClientUDPPacket{
-    ServerShutdown,
-    ServerError( String ),
-    ServerDesire( String ),
+    Disconnected( DisconnectionCode, String ), //farewell, the same as ServerToClientTCPPacket::Disconnected, may be lost
 
+    InitializationAccepted( u64 ), //session, client should write it into each datagram
+    Ping, //client answers ClientToServerUDPPacket::Pong, so server knows, that it is alive
+    ClockSyncRequest( u64 ), //time of server, client answers ClientToServerUDPPacket::ClockSyncResponse with it
 }
 
 impl ServerToClientUDPPacket{
-    pub fn pack(&self, token:u16, code:u64) -> Vec<u8>{
+    pub fn pack(&self, session:u64) -> Result< Vec<u8>, String>{
         let bufferLength=match *self{
-            ServerToClientTCPPacket::ServerShutdown => 16,
-            ServerToClientTCPPacket::ServerError( _ ) => 64,
-            ServerToClientTCPPacket::ServerDesire( _ ) => 64,
+            ServerToClientUDPPacket::Disconnected( _, _ ) => 96,
 
-            ServerToClientTCPPacket::LoginOrRegister => 16,
-            ServerToClientTCPPacket::InitializeUDPConnection ( _ ) => 16,
+            ServerToClientUDPPacket::InitializationAccepted( _ ) => 48,
+            ServerToClientUDPPacket::Ping => 32,
+            ServerToClientUDPPacket::ClockSyncRequest( _ ) => 40,
         };
 
         let mut buffer:Vec<u8>=Vec::with_capacity(bufferLength);
 
-        unsafe { buffer.set_len(4); }
+        packUDPHeader(&mut buffer, session);
 
-        match encode_into(self, &mut buffer, SizeLimit::Bounded(MESSAGE_TO_SERVER_LIMIT) ){
-            Ok ( _ ) =>{
-                let packetLength=buffer.len() as u32 - 4;
-                BigEndian::write_u32(&mut buffer[0..4], packetLength);
-            },
-            Err( _ ) =>
-                BigEndian::write_u32(&mut buffer[0..4], 0),
+        match encode_into(self, &mut buffer, SizeLimit::Bounded(MESSAGE_TO_CLIENT_LIMIT-UDP_PACKET_HEADER_LENGTH as u64) ){
+            Ok ( _ ) =>Ok(buffer),
+            Err( e ) =>Err( format!("Packet serialization error : {:?}, (maybe it's length is more than {}?)", e, MESSAGE_TO_CLIENT_LIMIT) ),
         }
+    }
+
+    ///Datagram without packet, it only carries acks
+    pub fn packAck(session:u64) -> Vec<u8>{
+        let mut buffer:Vec<u8>=Vec::with_capacity(UDP_PACKET_HEADER_LENGTH);
+
+        packUDPHeader(&mut buffer, session);
 
         buffer
     }
 
-    pub fn unpack(message:&Vec<u8>) -> Result<ServerToClientTCPPacket, String>{
-        match decode(&message[..]){
+    pub fn unpack(message:&Vec<u8>) -> Result<ServerToClientUDPPacket, &'static str>{
+        match decode(&message[UDP_PACKET_HEADER_LENGTH..]){
             Ok ( p ) => Ok ( p ),
-            Err( e ) => Err( String::from("deserialization error") ),
+            Err( e ) => Err("deserialization error"),
         }
     }
 }
-*/
+
+//session and time of server, reliability part of header is filled by UDPConnection
+fn packUDPHeader(buffer:&mut Vec<u8>, session:u64){
+    buffer.resize(UDP_PACKET_HEADER_LENGTH, 0);
+
+    BigEndian::write_u64(&mut buffer[0..8], session);
+    BigEndian::write_u64(&mut buffer[8..16], getServerTime());
+}
+
+///First 8 bytes of server query datagram instead of session. Lower 16 bits are more than any playerID, so query is never taken for a session
+pub const SERVER_QUERY_MAGIC: u64 = 0x5155_4552_5953_FFFF;
+
+///Query is padded by client up to this length, so response is never much longer than request
+pub const SERVER_QUERY_REQUEST_LENGTH: usize = 64;
+const SERVER_QUERY_RESPONSE_LIMIT: u64 = 256;
+
+///Answer to server query, it does not create session.
+///Datagram of query: magic(8) time of client(8) zeros up to SERVER_QUERY_REQUEST_LENGTH
+///Datagram of answer: magic(8) time of client from query(8) ServerInfo
+#[derive(RustcEncodable, RustcDecodable)]
+pub struct ServerInfo{
+    pub name:String,
+    pub map:String,
+    pub mode:String,
+    pub playersCount:usize,
+    pub playersLimit:usize,
+    pub gameVersion:Version,
+    pub mods:Vec<String>,
+}
+
+impl ServerInfo{
+    ///Map and mods have no length limit in config, so the last mods are dropped and map is cut, while answer does not fit.
+    ///Name and mode are limited by ServerConfig, so answer without mods and map always fits
+    pub fn fitIntoAnswer(&mut self) {
+        let limit=SERVER_QUERY_RESPONSE_LIMIT-16;
+
+        while encoded_size(self)>limit && self.mods.len()>0 {
+            self.mods.pop();
+        }
+
+        let size=encoded_size(self);
+
+        if size>limit {
+            let mut length=self.map.len().saturating_sub( (size-limit) as usize );
+
+            while !self.map.is_char_boundary(length) {
+                length-=1;
+            }
+
+            self.map.truncate(length);
+        }
+    }
+
+    pub fn pack(&self, magic:u64, clientTime:u64) -> Result< Vec<u8>, String>{
+        packQueryAnswer(self, magic, clientTime)
+    }
+
+    pub fn unpack(message:&Vec<u8>) -> Result<ServerInfo, &'static str>{
+        unpackQueryAnswer(message)
+    }
+}
+
+///First 8 bytes of LAN discovery query
+pub const LAN_DISCOVERY_QUERY_MAGIC: u64 = 0x4C41_4E51_5259_FFFF;
+///First 8 bytes of beacon and answer to LAN discovery query, it differs from query, so servers do not answer to beacons of each other
+pub const LAN_DISCOVERY_MAGIC: u64 = 0x4C41_4E44_4953_FFFF;
+
+///Beacon, that server sends to broadcast or multicast address, and answer to LAN discovery query.
+///Query and answer have the same layout as server query, beacon has 0 instead of time of client
+#[derive(RustcEncodable, RustcDecodable)]
+pub struct LANServerAnnouncement{
+    pub address:String, //empty, if client should use address, from which the datagram has come
+    pub gamePort:u16,
+    pub editorPort:u16,
+    pub isEditor:bool,
+    pub name:String,
+    pub playersCount:usize,
+    pub playersLimit:usize,
+}
+
+impl LANServerAnnouncement{
+    pub fn pack(&self, magic:u64, clientTime:u64) -> Result< Vec<u8>, String>{
+        packQueryAnswer(self, magic, clientTime)
+    }
+
+    pub fn unpack(message:&Vec<u8>) -> Result<LANServerAnnouncement, &'static str>{
+        unpackQueryAnswer(message)
+    }
+}
+
+fn packQueryAnswer<T:Encodable>(answer:&T, magic:u64, clientTime:u64) -> Result< Vec<u8>, String>{
+    let mut buffer:Vec<u8>=Vec::with_capacity(SERVER_QUERY_RESPONSE_LIMIT as usize);
+
+    buffer.resize(16, 0);
+    BigEndian::write_u64(&mut buffer[0..8], magic);
+    BigEndian::write_u64(&mut buffer[8..16], clientTime);
+
+    match encode_into(answer, &mut buffer, SizeLimit::Bounded(SERVER_QUERY_RESPONSE_LIMIT-16) ){
+        Ok ( _ ) =>Ok(buffer),
+        Err( e ) =>Err( format!("Server info serialization error : {:?}, (maybe it's length is more than {}?)", e, SERVER_QUERY_RESPONSE_LIMIT) ),
+    }
+}
+
+fn unpackQueryAnswer<T:Decodable>(message:&Vec<u8>) -> Result<T, &'static str>{
+    if message.len()<16 {
+        return Err("datagram is too short");
+    }
+
+    match decode(&message[16..]){
+        Ok ( p ) => Ok ( p ),
+        Err( e ) => Err("deserialization error"),
+    }
+}
@@ -11,30 +11,68 @@ use mio::*;
 use mio::tcp::*;
 use slab::Slab;
 use std::net::SocketAddr;
+use std::collections::VecDeque;
 
-use server::{Server,DisconnectionReason,DisconnectionSource};
+use server::{Server,DisconnectionReason,DisconnectionSource,getServerTime};
 
 use packet::{ClientToServerTCPPacket, ClientToServerUDPPacket, ServerToClientTCPPacket, ServerToClientUDPPacket};
+use udpConnection::UDPDelivery;
+use tcpServer::TCPServerMessage;
+use udpServer::UDPServerMessage;
+use roles::Role;
+
+/*
+Синхронизация часов(как в NTP):
+t0 - сервер отправил ClockSyncRequest(t0)
+t1 - клиент получил его, t2 - клиент отправил ClockSyncResponse(t0, t1)(время в заголовке)
+t3 - сервер получил ответ
+offset = ((t1-t0) + (t2-t3))/2 - на сколько часы клиента спешат
+rtt = (t3-t0) - (t2-t1)
+Из последних замеров берем тот, у которого rtt меньше всего, тк у него меньше всего погрешность.
+Ответ принимаем, только если t0 - время одного из наших неотвеченных запросов, иначе клиент мог бы задать любой offset.
+*/
+
+const CLOCK_SYNC_SAMPLES_LIMIT: usize = 8;
+const CLOCK_SYNC_INTERVAL: u64 = 10_000; //ms, after first CLOCK_SYNC_SAMPLES_LIMIT samples
+const CLOCK_SYNC_RESPONSE_TIMEOUT: u64 = 5_000; //ms, older answers are ignored
 
 pub struct Player{
-    isActive:bool,
+    pub isActive:bool,
+    pub isConnected:bool, //false, while TCP connection is lost and client may resume session
     server:Arc<Server>,
 
-    playerID:usize,
-    userID:usize,
-    userName:String,
+    pub playerID:usize,
+    pub userID:usize,
+    pub userName:String,
+    pub role:Role, //from roles.cfg, it is taken, when player joins
+
+    pub clockOffset:i64, //ms, time of client - time of server
+    pub rtt:u64, //ms
+    pub isClockSynchronized:bool,
+    clockSyncSamples:VecDeque<(i64, u64)>, //offset, rtt
+    clockSyncRequestTime:u64,
+    pendingClockSyncRequests:VecDeque<u64>, //t0 of requests, which have not been answered yet
 }
 
 
 impl Player{
-    pub fn new(server:Arc<Server>, playerID:usize, userID:usize, userName:String) -> Player {
+    pub fn new(server:Arc<Server>, playerID:usize, userID:usize, userName:String, role:Role) -> Player {
         Player{
             isActive:true,
+            isConnected:true,
             server:server,
 
             playerID:playerID,
             userID:userID,
             userName:userName,
+            role:role,
+
+            clockOffset:0,
+            rtt:0,
+            isClockSynchronized:false,
+            clockSyncSamples:VecDeque::with_capacity(CLOCK_SYNC_SAMPLES_LIMIT),
+            clockSyncRequestTime:0,
+            pendingClockSyncRequests:VecDeque::with_capacity(CLOCK_SYNC_SAMPLES_LIMIT),
         }
     }
 
@@ -45,8 +83,7 @@ impl Player{
     pub fn disconnect(&mut self, reason:DisconnectionReason){
         self._disconnect(reason.clone());
 
-        self.server.tryDisconnectTCPConnection( self.playerID, reason.clone() );
-        self.server.tryDisconnectUDPConnection( self.playerID, reason.clone() );
+        self.server.disconnect( self.playerID, reason, DisconnectionSource::Player );
     }
 
 
@@ -93,12 +130,18 @@ impl Player{
         let mut playersGuard=server.players.write().unwrap();
         */
 
-    pub fn sendDatagram(playerID:usize, datagram:&Vec<u8>){
-        //не паникует, если не находит udpConnection
+    pub fn sendDatagram(server:&Server, playerID:usize, packet:ServerToClientUDPPacket, delivery:UDPDelivery) {
+        //если udpConnection уже нет, UDPServer просто проигнорирует сообщение
+        server.sendToUDPServer( UDPServerMessage::Send(playerID, packet, delivery) );
     }
 
-    pub fn sendMessage(playerID:usize, message:Vec<u8>){
-        //не паникует, если не находит tcpConnection
+    pub fn sendMessage(server:&Server, playerID:usize, message:Vec<u8>){
+        //если tcpConnection уже нет, TCPServer просто проигнорирует сообщение
+        server.sendToTCPServer( TCPServerMessage::Send(playerID, message) );
+    }
+
+    pub fn send(&self, packet:ServerToClientTCPPacket){
+        Player::sendMessage(&self.server, self.playerID, packet.pack());
     }
 
     pub fn processMessage(&mut self, packet:&ClientToServerTCPPacket) -> Result<(), String> {
@@ -107,7 +150,70 @@ impl Player{
         Ok(())
     }
 
-    pub fn processDatagram(&mut self, packet:&ClientToServerUDPPacket, time:u64) {
-        println!("process UDP packet {}", time);
+    pub fn processDatagram(&mut self, packet:&ClientToServerUDPPacket, time:u64, receiveTime:u64) {
+        match *packet {
+            ClientToServerUDPPacket::ClockSyncResponse( requestTime, clientReceiveTime ) =>
+                self.processClockSyncResponse(requestTime, clientReceiveTime, time, receiveTime),
+            _ => println!("process UDP packet {}", self.toServerTime(time)),
+        }
+    }
+
+    ///Sends ClockSyncRequest, if it is time to do it. Until enough samples are collected, it is done on each call
+    pub fn synchronizeClock(&mut self) {
+        let time=getServerTime();
+
+        if self.clockSyncSamples.len()>=CLOCK_SYNC_SAMPLES_LIMIT && time.saturating_sub(self.clockSyncRequestTime)<CLOCK_SYNC_INTERVAL {
+            return;
+        }
+
+        self.clockSyncRequestTime=time;
+
+        if self.pendingClockSyncRequests.len()>=CLOCK_SYNC_SAMPLES_LIMIT {
+            self.pendingClockSyncRequests.pop_front();
+        }
+
+        self.pendingClockSyncRequests.push_back(time);
+
+        Player::sendDatagram(&self.server, self.playerID, ServerToClientUDPPacket::ClockSyncRequest(time), UDPDelivery::Unreliable);
+    }
+
+    fn processClockSyncResponse(&mut self, requestTime:u64, clientReceiveTime:u64, clientSendTime:u64, receiveTime:u64) {
+        //ответ на чужой или уже отвеченный запрос
+        match self.pendingClockSyncRequests.iter().position(|&t| t==requestTime) {
+            Some( index ) => {self.pendingClockSyncRequests.remove(index);},
+            None => return,
+        }
+
+        //ответ на слишком старый запрос
+        if requestTime>receiveTime || receiveTime-requestTime>CLOCK_SYNC_RESPONSE_TIMEOUT || clientSendTime<clientReceiveTime {
+            return;
+        }
+
+        let roundTime=receiveTime-requestTime;
+        let clientProcessingTime=clientSendTime-clientReceiveTime;
+
+        if clientProcessingTime>roundTime {
+            return;
+        }
+
+        let rtt=roundTime-clientProcessingTime;
+        let offset=( (clientReceiveTime as i64-requestTime as i64) + (clientSendTime as i64-receiveTime as i64) )/2;
+
+        if self.clockSyncSamples.len()>=CLOCK_SYNC_SAMPLES_LIMIT {
+            self.clockSyncSamples.pop_front();
+        }
+
+        self.clockSyncSamples.push_back( (offset, rtt) );
+
+        let &(bestOffset, bestRTT)=self.clockSyncSamples.iter().min_by_key(|&&(_, rtt)| rtt).unwrap();
+
+        self.clockOffset=bestOffset;
+        self.rtt=bestRTT;
+        self.isClockSynchronized=true;
+    }
+
+    ///Converts time of client(for example, from header of datagram) into time of server
+    pub fn toServerTime(&self, clientTime:u64) -> u64 {
+        (clientTime as i64-self.clockOffset) as u64
     }
 }
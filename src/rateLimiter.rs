@@ -0,0 +1,161 @@
+use std::net::IpAddr;
+use std::collections::HashMap;
+
+use description::Map;
+use log::Log;
+
+use server::getServerTime;
+
+#[derive(PartialEq, Copy, Clone)]
+pub struct RateLimit{
+    pub messagesPerSecond:f64,
+    pub messagesBurst:f64,
+    pub bytesPerSecond:f64,
+    pub bytesBurst:f64,
+}
+
+impl RateLimit{
+    pub fn read(map:&Map) -> Result<RateLimit, String> {
+        let rateLimit=RateLimit{
+            messagesPerSecond:try!(map.getStringAs::<f64>("messagesPerSecond")),
+            messagesBurst:try!(map.getStringAs::<f64>("messagesBurst")),
+            bytesPerSecond:try!(map.getStringAs::<f64>("bytesPerSecond")),
+            bytesBurst:try!(map.getStringAs::<f64>("bytesBurst")),
+        };
+
+        if rateLimit.messagesPerSecond<=0.0 || rateLimit.bytesPerSecond<=0.0 {
+            return Err( String::from("Rate of rate limit must be more than 0") );
+        }
+
+        if rateLimit.messagesBurst<1.0 || rateLimit.bytesBurst<1.0 {
+            return Err( String::from("Burst of rate limit must be at least 1") );
+        }
+
+        Ok(rateLimit)
+    }
+}
+
+///Bucket gets `rate` tokens per second, but can not hold more than `capacity`
+pub struct TokenBucket{
+    capacity:f64,
+    rate:f64,
+    tokens:f64,
+    updateTime:u64, //ms
+}
+
+impl TokenBucket{
+    pub fn new(capacity:f64, rate:f64) -> TokenBucket {
+        TokenBucket{
+            capacity:capacity,
+            rate:rate,
+            tokens:capacity,
+            updateTime:getServerTime(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let time=getServerTime();
+
+        if time>self.updateTime {
+            self.tokens+=(time-self.updateTime) as f64 * self.rate / 1000.0;
+
+            if self.tokens>self.capacity {
+                self.tokens=self.capacity;
+            }
+
+            self.updateTime=time;
+        }
+    }
+
+    ///Returns false, if bucket has not enough tokens, in this case tokens are not taken
+    pub fn take(&mut self, amount:f64) -> bool {
+        self.refill();
+
+        if self.tokens<amount {
+            return false;
+        }
+
+        self.tokens-=amount;
+
+        true
+    }
+
+    ///Full bucket is the same as the new one, so it can be removed
+    pub fn isFull(&mut self) -> bool {
+        self.refill();
+
+        self.tokens>=self.capacity
+    }
+}
+
+///Limits messages and bytes. Buckets are recreated, when other limit(for example, of next TCPConnectionStage) is used
+pub struct RateLimiter{
+    limit:RateLimit,
+    messages:TokenBucket,
+    bytes:TokenBucket,
+}
+
+impl RateLimiter{
+    pub fn new(limit:&RateLimit) -> RateLimiter {
+        RateLimiter{
+            limit:limit.clone(),
+            messages:TokenBucket::new(limit.messagesBurst, limit.messagesPerSecond),
+            bytes:TokenBucket::new(limit.bytesBurst, limit.bytesPerSecond),
+        }
+    }
+
+    pub fn take(&mut self, limit:&RateLimit, length:usize) -> Result<(), &'static str> {
+        if *limit!=self.limit {
+            *self=RateLimiter::new(limit);
+        }
+
+        if !self.messages.take(1.0) {
+            return Err("Rate limit of messages has been exceeded");
+        }
+
+        if !self.bytes.take(length as f64) {
+            return Err("Rate limit of bytes has been exceeded");
+        }
+
+        Ok(())
+    }
+
+    pub fn isFull(&mut self) -> bool {
+        self.messages.isFull() && self.bytes.isFull()
+    }
+}
+
+///Limiter for each IP, is exceeding reported
+pub type IPRateLimiters = HashMap<IpAddr, (RateLimiter, bool)>;
+
+///Returns false, if this IP sends too many datagrams of this kind. Exceeding is logged once, until limiter gets full again
+pub fn takeIPRateLimit(log:&Log, rateLimiters:&mut IPRateLimiters, rateLimit:&RateLimit, ip:IpAddr, length:usize, datagramsName:&str) -> bool {
+    let &mut (ref mut rateLimiter, ref mut isReported)=rateLimiters.entry(ip).or_insert_with(|| (RateLimiter::new(rateLimit), false));
+
+    match rateLimiter.take(rateLimit, length) {
+        Ok ( _ ) => true,
+        Err( e ) => {
+            if !*isReported {
+                *isReported=true;
+                log.print( format!("[ERROR] {} from {} : {}", datagramsName, ip, e) );
+            }
+
+            false
+        },
+    }
+}
+
+//полные limiter-ы ничем не отличаются от новых
+pub fn removeFullRateLimiters(rateLimiters:&mut IPRateLimiters) {
+    let mut fullRateLimiters=Vec::new();
+
+    for (ip, &mut (ref mut rateLimiter, _)) in rateLimiters.iter_mut() {
+        if rateLimiter.isFull() {
+            fullRateLimiters.push(ip.clone());
+        }
+    }
+
+    for ip in fullRateLimiters {
+        rateLimiters.remove(&ip);
+    }
+}
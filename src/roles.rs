@@ -0,0 +1,131 @@
+use std::fs::File;
+use std::error::Error;
+use std::io::{Read, ErrorKind};
+
+use std::collections::HashMap;
+
+use description;
+use description::Map;
+
+/*
+Роли хранятся в roles.cfg в формате description:
+player = [ players ]
+moderator = [ players, kick, broadcast ]
+admin = [ all ]
+users = [ { userID = "1073741825", role = admin }
+{ userID = "1073741826", role = moderator } ]
+Права - имена команд из command.rs, all - все команды. Кого нет в users, тот player.
+Роли проверяются у команд игроков(ClientToServerTCPPacket::Command), оператор(консоль и server_admin) может все.
+Если файла нет, все игроки - player без прав.
+*/
+
+const ROLES_FILE_NAME: &'static str = "roles.cfg";
+const ALL_PERMISSIONS: &'static str = "all";
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum Role{
+    Player,
+    Moderator,
+    Admin, //also may take reserved slots, see queue.reservedSlots
+}
+
+impl Role{
+    pub fn parse(role:&str) -> Result<Role, String> {
+        match role {
+            "player" => Ok( Role::Player ),
+            "moderator" => Ok( Role::Moderator ),
+            "admin" => Ok( Role::Admin ),
+            _ => Err( format!("Unknown role \"{}\", expected player, moderator or admin", role) ),
+        }
+    }
+
+    pub fn print(&self) -> &'static str {
+        match *self {
+            Role::Player => "player",
+            Role::Moderator => "moderator",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+pub struct Roles{
+    playerPermissions:Vec<String>,
+    moderatorPermissions:Vec<String>,
+    adminPermissions:Vec<String>,
+    users:HashMap<usize, Role>, //userID
+}
+
+impl Roles{
+    pub fn load() -> Result<Roles, String> {
+        let mut file=match File::open(ROLES_FILE_NAME) {
+            Ok( f ) => f,
+            Err( ref e ) if e.kind()==ErrorKind::NotFound => {
+                return Ok(
+                    Roles{
+                        playerPermissions:Vec::new(),
+                        moderatorPermissions:Vec::new(),
+                        adminPermissions:Vec::new(),
+                        users:HashMap::new(),
+                    }
+                );
+            },
+            Err( e ) => return Err(format!("Can not read file \"{}\" : {}", ROLES_FILE_NAME, e.description())),
+        };
+
+        let mut content = String::new();
+        match file.read_to_string(&mut content){
+            Ok( _ )  => {},
+            Err( e ) => return Err(format!("Can not read file \"{}\" : {}", ROLES_FILE_NAME, e.description())),
+        }
+
+        description::parse( &content, |root| {
+            let mut users=HashMap::new();
+
+            for userValue in try!(root.getList("users")).iter() {
+                let userMap=try!(userValue.getMap());
+
+                users.insert(
+                    try!(userMap.getStringAs::<usize>("userID")),
+                    try!(Role::parse( try!(userMap.getString("role")) ))
+                );
+            }
+
+            Ok(
+                Roles{
+                    playerPermissions:try!(readPermissions(&root, "player")),
+                    moderatorPermissions:try!(readPermissions(&root, "moderator")),
+                    adminPermissions:try!(readPermissions(&root, "admin")),
+                    users:users,
+                }
+            )
+        }).or_else(|e| Err(format!("File \"{}\" : {}", ROLES_FILE_NAME, e)))
+    }
+
+    pub fn getRole(&self, userID:usize) -> Role {
+        match self.users.get(&userID) {
+            Some( role ) => *role,
+            None => Role::Player,
+        }
+    }
+
+    ///commandName is the first word of command, like kick
+    pub fn isAllowed(&self, role:Role, commandName:&str) -> bool {
+        let permissions=match role {
+            Role::Player => &self.playerPermissions,
+            Role::Moderator => &self.moderatorPermissions,
+            Role::Admin => &self.adminPermissions,
+        };
+
+        permissions.iter().any(|permission| permission==ALL_PERMISSIONS || permission==commandName)
+    }
+}
+
+fn readPermissions(root:&Map, roleName:&str) -> Result<Vec<String>, String> {
+    let mut permissions=Vec::new();
+
+    for permission in try!(root.getList(roleName)).iter() {
+        permissions.push( try!(permission.getString()).clone() );
+    }
+
+    Ok(permissions)
+}
@@ -1,14 +1,10 @@
 use std::thread;
 use std::thread::JoinHandle;
 use std::sync::{Mutex,Arc,RwLock,Weak};
+use std::sync::mpsc;
+use std::time::Duration;
 
-use std::io::{self, ErrorKind};
-use std::rc::Rc;
-
-use mio::*;
-use mio::tcp::*;
-use mio::udp::*;
-use slab::Slab;
+use mio::channel;
 use std::net::SocketAddr;
 
 use time::get_time;
@@ -57,15 +53,34 @@ achtung!! в случае try ошибки не вызовется disconnect у
 надо переделать основную функцию примерно как на клиенте
 */
 
+/*
+Сейчас каждый поток владеет своими данными: TCPServer - TCP соединениями, UDPServer - UDP соединениями, Game - игроками.
+Друг с другом они общаются только сообщениями через каналы, поэтому никто не блокирует чужие данные и взаимных блокировок нет.
+У соединений и игрока одной сессии один индекс - sessionID(он же Token TCP соединения и playerID).
+
+Disconnect:
+тот, кто обнаружил причину, отключает себя сам(_disconnect) и через Server::disconnect сообщает остальным двум,
+получив Disconnect, модуль отключает только себя.
+*/
+
 use appData::AppData;
-use player::Player;
 
-use tcpServer::TCPServer;
-use tcpConnection::TCPConnection;
+use tcpServer::{TCPServer, TCPServerMessage};
+use udpServer::{UDPServer, UDPServerMessage};
+use game::{Game, GameMessage};
+use packet::DisconnectionCode;
+use command::{Command, CommandResult, STOP_COUNTDOWN_LIMIT};
+use banList::Ban;
 
-use udpServer::{UDPSocket, UDPServer, UDP_DATAGRAM_LENGTH_LIMIT};
-use udpConnection::UDPConnection;
+const SERVER_THREADS_NUMBER: usize = 3; //TCPServer, UDPServer, Game
+const COMMAND_ANSWER_TIMEOUT: u64 = 2; //sec
 
+///Time of server in milliseconds, it is written into each datagram
+pub fn getServerTime() -> u64 {
+    let time=get_time();
+
+    (time.sec*1000 + (time.nsec/1_000_000) as i64) as u64
+}
 
 #[derive(PartialEq, Eq, Copy, Clone)]
 pub enum ServerState{
@@ -74,6 +89,7 @@ pub enum ServerState{
     Shutdown,
     TCPError,
     UDPError,
+    GameError,
 }
 
 #[derive(PartialEq, Eq, Clone)]
@@ -82,9 +98,24 @@ pub enum DisconnectionReason{
     ServerShutdown,
     FatalError ( &'static str  ),
     ClientDesire ( String ),
-    ServerDesire ( String ),
+    ServerDesire ( DisconnectionCode, String ),
     ClientError ( String ),
-    ServerError( String ),
+    ServerError( DisconnectionCode, String ),
+}
+
+impl DisconnectionReason{
+    ///Code, which client gets in ServerToClientTCPPacket::Disconnected
+    pub fn code(&self) -> DisconnectionCode {
+        match *self {
+            DisconnectionReason::Hup => DisconnectionCode::Unknown,
+            DisconnectionReason::ServerShutdown => DisconnectionCode::ServerShutdown,
+            DisconnectionReason::FatalError( _ ) => DisconnectionCode::ServerError,
+            DisconnectionReason::ClientDesire( _ ) => DisconnectionCode::Unknown,
+            DisconnectionReason::ServerDesire( code, _ ) => code,
+            DisconnectionReason::ClientError( _ ) => DisconnectionCode::ProtocolError,
+            DisconnectionReason::ServerError( code, _ ) => code,
+        }
+    }
 }
 
 #[derive(PartialEq, Eq, Clone)]
@@ -100,19 +131,14 @@ pub struct Server{
 
     pub tcpServerJoinHandle:Mutex<Option<JoinHandle<()>>>,
     pub udpServerJoinHandle:Mutex<Option<JoinHandle<()>>>,
+    pub gameJoinHandle:Mutex<Option<JoinHandle<()>>>,
 
-    pub udpSocket:Mutex<UDPSocket>,
-
-    //all have one index - Token
-    pub tcpConnections: RwLock<Slab< Mutex<TCPConnection>, Token>>,
-    pub udpConnections: RwLock<Slab< Mutex<UDPConnection>, usize>>,
-    pub players: RwLock<Slab< RwLock<Player>, usize>>,
+    tcpServerSender:Mutex<channel::Sender<TCPServerMessage>>,
+    udpServerSender:Mutex<channel::Sender<UDPServerMessage>>,
+    gameSender:Mutex<mpsc::Sender<GameMessage>>,
 
     pub playersCount: RwLock<usize>,
-
-    pub disconnectTCPConnectionsList:Mutex<Vec<(usize, DisconnectionReason)>>,
-    pub disconnectUDPConnectionsList:Mutex<Vec<(usize, DisconnectionReason)>>,
-    pub disconnectPlayersList:Mutex<Vec<(usize, DisconnectionReason)>>,
+    pub playersLimit: RwLock<usize>, //operator can change it by command playersLimit
 }
 
 impl Server{
@@ -128,7 +154,9 @@ impl Server{
         let serverAddress=format!("{}:{}", appData.serverConfig.server_address, port);
         let addr = try!((&serverAddress).parse::<SocketAddr>().or( Err(format!("Can not use server address : {}", &serverAddress)) ));
 
-        let udpSocket=try!( UDPSocket::new(addr.clone()) );
+        let (tcpServerSender, tcpServerReceiver)=channel::channel();
+        let (udpServerSender, udpServerReceiver)=channel::channel();
+        let (gameSender, gameReceiver)=mpsc::channel();
 
         let server=Server{
             appData:Arc::downgrade(&appData),
@@ -136,45 +164,21 @@ impl Server{
 
             tcpServerJoinHandle:Mutex::new(None),
             udpServerJoinHandle:Mutex::new(None),
+            gameJoinHandle:Mutex::new(None),
 
-            udpSocket:Mutex::new(udpSocket),
-
-            tcpConnections:RwLock::new(Slab::with_capacity(appData.serverConfig.server_connectionsLimit)),
-            udpConnections:RwLock::new(Slab::with_capacity(appData.serverConfig.server_connectionsLimit)),
-            players:RwLock::new(Slab::with_capacity(appData.serverConfig.server_connectionsLimit)),
+            tcpServerSender:Mutex::new(tcpServerSender),
+            udpServerSender:Mutex::new(udpServerSender),
+            gameSender:Mutex::new(gameSender),
 
             playersCount:RwLock::new(0),
-
-            disconnectTCPConnectionsList:Mutex::new(Vec::new()),
-            disconnectUDPConnectionsList:Mutex::new(Vec::new()),
-            disconnectPlayersList:Mutex::new(Vec::new()),
+            playersLimit:RwLock::new(appData.serverConfig.server_playersLimit),
         };
 
         let server=Arc::new(server);
 
-        let mut tcpServer=TCPServer{
-            appData:appData.clone(),
-            server:server.clone(),
-            listener:try!(TcpListener::bind(&addr).or( Err(format!("Can not create TCP socket with address : {}", &serverAddress)) )),
-            poll:try!(Poll::new().or( Err(format!("Can not create TCP event poll")) )),
-            token:Token(10_000_000),
-            events:Events::with_capacity(appData.serverConfig.server_connectionsLimit*8),
-            tickTime:get_time().sec,
-        };
-
-
-        let mut udpServer=UDPServer{
-            appData:appData.clone(),
-            server:server.clone(),
-            poll:try!(Poll::new().or( Err(format!("Can not create UDP event poll")) )),
-            events:Events::with_capacity(appData.serverConfig.server_playersLimit*8),
-            readBuffer:Vec::with_capacity(UDP_DATAGRAM_LENGTH_LIMIT),
-
-            sessions:Vec::with_capacity(appData.serverConfig.server_playersLimit),
-            tickTime:get_time().sec,
-        };
-
-        udpServer.sessions.resize(appData.serverConfig.server_playersLimit, 0);
+        let mut tcpServer=try!(TCPServer::new(appData.clone(), server.clone(), &addr, tcpServerReceiver));
+        let mut udpServer=try!(UDPServer::new(appData.clone(), server.clone(), &addr, udpServerReceiver));
+        let mut game=Game::new(appData.clone(), server.clone(), gameReceiver);
 
         let tcpServerJoinHandle=thread::spawn(move||{
             let (sendAbschiedMessage, thisThread)=match tcpServer.process(){
@@ -188,7 +192,7 @@ impl Server{
 
                     match state{
                         ServerState::Shutdown => (true, false),
-                        ServerState::UDPError => (false, false),
+                        ServerState::UDPError | ServerState::GameError => (false, false),
                         _ => (false,true),
                     }
                 }
@@ -200,7 +204,7 @@ impl Server{
 
             if thisThread {
                 *tcpServer.server.tcpServerJoinHandle.lock().unwrap()=None; //чтобы не было join самого себя
-                Server::stop(tcpServer.server);
+                Server::stop(tcpServer.server.clone());
             }
         });
 
@@ -216,7 +220,7 @@ impl Server{
 
                     match state{
                         ServerState::Shutdown => (true, false),
-                        ServerState::TCPError => (false, false),
+                        ServerState::TCPError | ServerState::GameError => (false, false),
                         _ => (false,true),
                     }
                 }
@@ -228,7 +232,34 @@ impl Server{
 
             if thisThread {
                 *udpServer.server.udpServerJoinHandle.lock().unwrap()=None; //чтобы не было join самого себя
-                Server::stop(udpServer.server);
+                Server::stop(udpServer.server.clone());
+            }
+        });
+
+        let gameJoinHandle=thread::spawn(move||{
+            let thisThread=match game.process(){
+                Err( e ) => {
+                    *game.server.state.write().unwrap()=ServerState::GameError;
+                    game.appData.log.print( format!("[INFO] Game error : {}", e) );
+                    true
+                },
+                Ok ( _ ) => {
+                    let state=*game.server.state.read().unwrap();
+
+                    match state{
+                        ServerState::Shutdown | ServerState::TCPError | ServerState::UDPError => false,
+                        _ => true,
+                    }
+                }
+            };
+
+            game.onServerShutdown();
+
+            game.appData.log.print(format!("[INFO] Game has been stoped"));
+
+            if thisThread {
+                *game.server.gameJoinHandle.lock().unwrap()=None; //чтобы не было join самого себя
+                Server::stop(game.server.clone());
             }
         });
 
@@ -243,6 +274,7 @@ impl Server{
 
         *server.tcpServerJoinHandle.lock().unwrap()=Some(tcpServerJoinHandle);
         *server.udpServerJoinHandle.lock().unwrap()=Some(udpServerJoinHandle);
+        *server.gameJoinHandle.lock().unwrap()=Some(gameJoinHandle);
 
         //а теперь добавляем данный модуль
         *appData.server.write().unwrap()=Some(server);
@@ -266,122 +298,165 @@ impl Server{
         }
 
         match server.udpServerJoinHandle.lock().unwrap().take(){
-            Some(th) => {th.join();},// подождем, тогда останется лишь одна ссылка на сервер - server
+            Some(th) => {th.join();},
+            None => {},
+        }
+
+        match server.gameJoinHandle.lock().unwrap().take(){
+            Some(th) => {th.join();},
             None => {},
         }
 
         //теперь вызовется drop для server
     }
 
-    pub fn getTCPConnectionAnd<T,F>(&self, token:Token, mut f:F) -> T where F:FnMut(&mut TCPConnection) -> T {
-        let tcpConnectionsGuard=self.tcpConnections.read().unwrap();
+    ///Each thread calls it when it is ready, the last one starts the server. Returns false, if server has been stopped during initialization
+    pub fn onThreadInitialized(&self) -> bool {
+        {
+            let mut serverStateGuard=self.state.write().unwrap();
+
+            match *serverStateGuard {
+                ServerState::Initialization( readyThreads ) => {
+                    if readyThreads+1==SERVER_THREADS_NUMBER {
+                        *serverStateGuard=ServerState::Processing;
+                    }else{
+                        *serverStateGuard=ServerState::Initialization( readyThreads+1 );
+                    }
+                },
+                ServerState::Processing => {},
+                _ => return false, //Disconnect кем-то другим
+            }
+        }
 
-        let mut connection=(*tcpConnectionsGuard)[token].lock().unwrap();
+        while match *self.state.read().unwrap() { ServerState::Initialization(_) => true, _=>false} {
+            thread::sleep_ms(10);
+        }
 
-        f( &mut (*connection) )
+        *self.state.read().unwrap()==ServerState::Processing
     }
 
-    pub fn getSafeTCPConnectionAnd<T,F>(&self, token:Token, mut f:F) -> Option<T> where F:FnMut(&mut TCPConnection) -> T {
-        let tcpConnectionsGuard=self.tcpConnections.read().unwrap();
-
-        match (*tcpConnectionsGuard).get(token){
-            Some( connectionMutex ) =>
-                Some( f( &mut (*connectionMutex).lock().unwrap() ) ),
-            None =>
-                None,
-        }
+    //если поток уже остановлен, сообщение просто теряется
+    pub fn sendToTCPServer(&self, message:TCPServerMessage) {
+        self.tcpServerSender.lock().unwrap().send(message);
     }
 
-    pub fn getSafeUDPConnectionAnd<T,F>(&self, sessionID:usize, mut f:F) -> Option<T> where F:FnMut(&mut UDPConnection) -> T {
-        let udpConnectionsGuard=self.udpConnections.read().unwrap();
+    pub fn sendToUDPServer(&self, message:UDPServerMessage) {
+        self.udpServerSender.lock().unwrap().send(message);
+    }
 
-        match (*udpConnectionsGuard).get(sessionID){
-            Some( connection ) =>
-                Some( f( &mut (*connection).lock().unwrap() ) ),
-            None =>
-                None,
-        }
+    pub fn sendToGame(&self, message:GameMessage) {
+        self.gameSender.lock().unwrap().send(message);
     }
 
-    pub fn getSafePlayerAnd<T,F>(&self, sessionID:usize, mut f:F) -> Option<T> where F:FnMut(&mut Player) -> T {
-        let playersGuard=self.players.read().unwrap();
+    ///Sends command to the thread, which owns needed data, and waits for its answer. Called by admin server and console
+    pub fn executeCommand(&self, command:Command) -> CommandResult {
+        let (sender, receiver)=mpsc::channel();
+
+        match command {
+            Command::ListConnections =>
+                self.sendToTCPServer( TCPServerMessage::ListConnections(sender) ),
+            Command::ListPlayers =>
+                self.sendToGame( GameMessage::ListPlayers(sender) ),
+            Command::Kick( selector, reason ) =>
+                self.sendToGame( GameMessage::Kick(selector, reason, sender) ),
+            Command::Broadcast( text ) => {
+                self.sendToGame( GameMessage::Broadcast(text) );
+                return Ok( String::from("Notice has been sent") );
+            },
+            Command::SetPlayersLimit( limit ) => {
+                let appData=self.appData.upgrade().unwrap();
+                let serverConfig=&appData.serverConfig;
 
-        match (*playersGuard).get(sessionID){
-            Some( player ) =>
-                Some( f( &mut (*player).write().unwrap() ) ),
-            None =>
-                None,
-        }
-    }
+                //обычным игрокам должно остаться хоть одно место кроме зарезервированных, а игроков не больше, чем соединений
+                if limit<=serverConfig.queue_reservedSlots || limit>serverConfig.server_connectionsLimit {
+                    return Err( format!("Players limit must be from {} to {}", serverConfig.queue_reservedSlots+1, serverConfig.server_connectionsLimit) );
+                }
+
+                *self.playersLimit.write().unwrap()=limit;
+                return Ok( format!("Players limit is {}", limit) );
+            },
+            //TCPServer и Game сами отключат забаненных, когда увидят новую версию бан-листа
+            Command::Ban( target, duration, reason ) => {
+                let appData=self.appData.upgrade().unwrap();
+
+                let expires=if duration>0 {
+                    try!(duration.checked_mul(60).and_then(|seconds| get_time().sec.checked_add(seconds))
+                        .ok_or( format!("Ban duration {} minutes is too long", duration) ))
+                }else{
+                    0
+                };
+
+                let ban=Ban{
+                    target:target,
+                    expires:expires,
+                    reason:reason,
+                };
+
+                let answer=format!("{} has been banned", ban.target.print());
+                try!(appData.getStorageAnd(|storage| storage.banList.add(ban)));
+                appData.log.print( format!("[INFO] {}", answer) );
+
+                return Ok(answer);
+            },
+            Command::Unban( target ) => {
+                let appData=self.appData.upgrade().unwrap();
+
+                return if try!(appData.getStorageAnd(|storage| storage.banList.remove(&target))) {
+                    Ok( format!("{} has been unbanned", target.print()) )
+                }else{
+                    Err( format!("{} is not banned", target.print()) )
+                };
+            },
+            Command::ListBans => {
+                let appData=self.appData.upgrade().unwrap();
 
-    pub fn isTCPConnectionActive(&self, token:Token ) -> bool {
-        let tcpConnectionsGuard=self.tcpConnections.read().unwrap();
+                return Ok( appData.getStorageAnd(|storage| storage.banList.print()) );
+            },
+            Command::Stop( seconds ) => {
+                let appData=self.appData.upgrade().unwrap();
 
-        match (*tcpConnectionsGuard).get(token){
-            Some( connectionMutex ) =>
-                (*connectionMutex.lock().unwrap()).isActive,
-            None =>
-                false
-        }
-    }
+                let seconds=match seconds {
+                    Some( seconds ) => seconds,
+                    None => appData.serverConfig.shutdown_countdown,
+                };
 
-    pub fn tryDisconnectUDPConnection(&self, sessionID:usize, reason:DisconnectionReason) {
-        match self.udpConnections.try_read(){
-            Ok( udpConnectionsGuard ) => {
-                match (*udpConnectionsGuard).get( sessionID ){
-                    Some( udpConnectionMutex ) => {
-                        match udpConnectionMutex.try_lock(){
-                            Ok( udpConnectionGuard ) =>
-                                (*udpConnectionGuard)._disconnect(reason),
-                            Err( _ ) =>
-                                self.disconnectUDPConnectionsList.lock().unwrap().push( (sessionID,reason) ),
-                        }
-                    },
-                    None => {},
+                if seconds>STOP_COUNTDOWN_LIMIT {
+                    return Err( format!("Countdown must not be more than {} seconds", STOP_COUNTDOWN_LIMIT) );
                 }
+
+                //отсчет ведет Game, он же предупреждает игроков и сохраняет мир, даже если останавливаемся сразу
+                self.sendToGame( GameMessage::ScheduleShutdown(seconds) );
+
+                return if seconds==0 {
+                    Ok( String::from("Server is stopping") )
+                }else{
+                    Ok( format!("Server will be stopped in {} seconds", seconds) )
+                };
+            },
+            Command::CancelStop => {
+                self.sendToGame( GameMessage::CancelShutdown );
+                return Ok( String::from("Stop has been cancelled") );
             },
-            Err( _ ) =>
-                self.disconnectUDPConnectionsList.lock().unwrap().push( (sessionID,reason) ),
         }
-    }
 
-    pub fn tryDisconnectTCPConnection(&self, sessionID:usize, reason:DisconnectionReason) {
-        match self.tcpConnections.try_read(){
-            Ok( tcpConnectionsGuard ) => {
-                match (*tcpConnectionsGuard).get( Token(sessionID) ){
-                    Some( tcpConnectionMutex ) => {
-                        match tcpConnectionMutex.try_lock(){
-                            Ok( tcpConnectionGuard ) =>
-                                (*tcpConnectionGuard)._disconnect(reason),
-                            Err( _ ) =>
-                                self.disconnectTCPConnectionsList.lock().unwrap().push( (sessionID,reason) ),
-                        }
-                    },
-                    None => {},
-                }
-            },
-            Err( _ ) =>
-                self.disconnectTCPConnectionsList.lock().unwrap().push( (sessionID,reason) ),
+        match receiver.recv_timeout( Duration::new(COMMAND_ANSWER_TIMEOUT,0) ) {
+            Ok ( result ) => result,
+            Err( _ ) => Err( String::from("Server has not answered to command") ),
         }
     }
 
-    pub fn tryDisconnectPlayer(&self, sessionID:usize, reason:DisconnectionReason) {
-        match self.players.try_read(){
-            Ok( playersGuard ) => {
-                match (*playersGuard).get( sessionID ){
-                    Some( playerMutex ) => {
-                        match playerMutex.try_write(){
-                            Ok( playerGuard ) =>
-                                (*playerGuard)._disconnect(reason),
-                            Err( _ ) =>
-                                self.disconnectPlayersList.lock().unwrap().push( (sessionID,reason) ),
-                        }
-                    },
-                    None => {},
-                }
-            },
-            Err( _ ) =>
-                self.disconnectPlayersList.lock().unwrap().push( (sessionID,reason) ),
+    ///Disconnects the parts of session, except source, which has disconnected itself
+    pub fn disconnect(&self, sessionID:usize, reason:DisconnectionReason, source:DisconnectionSource) {
+        if source!=DisconnectionSource::TCP {
+            self.sendToTCPServer( TCPServerMessage::Disconnect(sessionID, reason.clone()) );
+        }
+
+        if source!=DisconnectionSource::UDP {
+            self.sendToUDPServer( UDPServerMessage::Disconnect(sessionID, reason.clone()) );
+        }
+
+        if source!=DisconnectionSource::Player {
+            self.sendToGame( GameMessage::Disconnect(sessionID, reason) );
         }
     }
 }
@@ -5,8 +5,12 @@ use std::io::prelude::*;
 use std::fs::File;
 
 use std::sync::{Mutex,RwLock,Arc,Barrier,Weak};
+use std::net::Ipv4Addr;
+use std::str::FromStr;
 
 use description;
+use description::Map;
+use rateLimiter::RateLimit;
 
 pub struct ServerConfig{
     pub server_adminPort:u16,
@@ -15,7 +19,39 @@ pub struct ServerConfig{
     pub server_address:String,
     pub server_connectionsLimit:usize,
     pub server_playersLimit:usize,
+    pub server_connectionsPerIPLimit:usize,
+    pub server_acceptsPerIPPerSecond:f64,
+    pub server_acceptsPerIPBurst:f64,
+    pub server_udpPingInterval:u64,
+    pub server_udpTimeout:u64,
+    pub server_resumeGraceTime:u64,
+    pub server_name:String,
+    pub server_mode:String,
+    pub rateLimit_waitingSessionID:RateLimit,
+    pub rateLimit_loadingPlayerData:RateLimit,
+    pub rateLimit_loginOrRegister:RateLimit,
+    pub rateLimit_udpConnectionInitialization:RateLimit,
+    pub rateLimit_playing:RateLimit,
+    pub rateLimit_udp:RateLimit,
+    pub rateLimit_udpHandshake:RateLimit, //for each IP
+    pub rateLimit_udpQuery:RateLimit, //for each IP
     pub repositories:RwLock<Vec<String>>,
+    pub masterServer_cooldown:u64,
+    pub masterServer_heartbeatInterval:u64, //sec
+    pub masterServer_heartbeatMaxBackoff:u64, //sec, the longest delay of registration or heartbeat after errors
+    pub lan_enabled:bool,
+    pub lan_reservedNames:Vec<String>,
+    pub lan_discoveryEnabled:bool, //works only in editor or LAN mode
+    pub lan_discoveryPort:u16,
+    pub lan_discoveryInterface:Ipv4Addr, //address of LAN interface, 0.0.0.0 - chosen by system
+    pub lan_discoveryAddress:Ipv4Addr, //broadcast address or multicast group, where beacons are sent
+    pub lan_discoveryInterval:u64, //sec, 0 - no beacons, only answers to queries
+    pub queue_length:usize, //authenticated clients wait for a free slot, 0 - server refuses them
+    pub queue_reservedSlots:usize, //part of playersLimit, that only admins(roles.cfg) may take
+    pub queue_positionUpdateInterval:i64, //sec
+    pub shutdown_countdown:u64, //sec, if operator has not set time of stop
+    pub shutdown_warnings:Vec<u64>, //sec before stop, when players are warned
+    pub shutdown_disconnectDelay:i64, //sec, how long clients may receive the last messages
     pub loadMap:String,
     pub generateMap:String,
 }
@@ -59,6 +95,80 @@ impl ServerConfig{
 
                         playersLimit
                     },
+                    server_connectionsPerIPLimit:{
+                        let connectionsPerIPLimit=try!(root.getStringAs::<usize>("server.connectionsPerIPLimit"));
+
+                        if connectionsPerIPLimit==0 {
+                            return Err(String::from("Number of connections per IP must be more than 0"));
+                        }
+
+                        connectionsPerIPLimit
+                    },
+                    server_acceptsPerIPPerSecond:{
+                        let acceptsPerSecond=try!(root.getStringAs::<f64>("server.acceptsPerIPPerSecond"));
+
+                        if acceptsPerSecond<=0.0 {
+                            return Err(String::from("Number of accepts per IP per second must be more than 0"));
+                        }
+
+                        acceptsPerSecond
+                    },
+                    server_acceptsPerIPBurst:{
+                        let acceptsBurst=try!(root.getStringAs::<f64>("server.acceptsPerIPBurst"));
+
+                        if acceptsBurst<1.0 {
+                            return Err(String::from("Burst of accepts per IP must be at least 1"));
+                        }
+
+                        acceptsBurst
+                    },
+                    server_udpPingInterval:{
+                        let pingInterval=try!(root.getStringAs::<u64>("server.udpPingInterval"));
+
+                        if pingInterval==0 {
+                            return Err(String::from("UDP ping interval must be more than 0"));
+                        }
+
+                        pingInterval
+                    },
+                    server_udpTimeout:{
+                        let timeout=try!(root.getStringAs::<u64>("server.udpTimeout"));
+                        let pingInterval=try!(root.getStringAs::<u64>("server.udpPingInterval"));
+
+                        if timeout<=pingInterval {
+                            return Err(format!("UDP timeout({}) must be more than UDP ping interval({})", timeout, pingInterval));
+                        }
+
+                        timeout
+                    },
+                    server_resumeGraceTime:try!(root.getStringAs::<u64>("server.resumeGraceTime")),
+                    server_name:{
+                        let name=try!(root.getString("server.name")).clone();
+
+                        //имя уходит в ответ на UDP запрос, который не должен быть большим
+                        if name.len()==0 || name.len()>64 {
+                            return Err(String::from("server.name must have from 1 to 64 bytes"));
+                        }
+
+                        name
+                    },
+                    server_mode:{
+                        let mode=try!(root.getString("server.mode")).clone();
+
+                        if mode.len()==0 || mode.len()>32 {
+                            return Err(String::from("server.mode must have from 1 to 32 bytes"));
+                        }
+
+                        mode
+                    },
+                    rateLimit_waitingSessionID:try!(readRateLimit(&root, "rateLimit.waitingSessionID")),
+                    rateLimit_loadingPlayerData:try!(readRateLimit(&root, "rateLimit.loadingPlayerData")),
+                    rateLimit_loginOrRegister:try!(readRateLimit(&root, "rateLimit.loginOrRegister")),
+                    rateLimit_udpConnectionInitialization:try!(readRateLimit(&root, "rateLimit.udpConnectionInitialization")),
+                    rateLimit_playing:try!(readRateLimit(&root, "rateLimit.playing")),
+                    rateLimit_udp:try!(readRateLimit(&root, "rateLimit.udp")),
+                    rateLimit_udpHandshake:try!(readRateLimit(&root, "rateLimit.udpHandshake")),
+                    rateLimit_udpQuery:try!(readRateLimit(&root, "rateLimit.udpQuery")),
                     repositories:{
                         let repositoriesList=try!(root.getList("repositories"));
 
@@ -70,6 +180,81 @@ impl ServerConfig{
 
                         RwLock::new(repositories)
                     },
+                    masterServer_cooldown:try!(root.getStringAs::<u64>("masterServer.cooldown")),
+                    masterServer_heartbeatInterval:{
+                        let heartbeatInterval=try!(root.getStringAs::<u64>("masterServer.heartbeatInterval"));
+
+                        if heartbeatInterval==0 {
+                            return Err(String::from("masterServer.heartbeatInterval must be more than 0"));
+                        }
+
+                        heartbeatInterval
+                    },
+                    masterServer_heartbeatMaxBackoff:try!(root.getStringAs::<u64>("masterServer.heartbeatMaxBackoff")),
+                    lan_enabled:{
+                        let lanEnabled=try!(root.getStringAs::<bool>("lan.enabled"));
+
+                        //чтобы на рабочем сервере этот режим нельзя было включить случайно
+                        if lanEnabled && !cfg!(feature="lan") {
+                            return Err(String::from("LAN mode is enabled, but server has been built without \"lan\" feature"));
+                        }
+
+                        lanEnabled
+                    },
+                    lan_reservedNames:{
+                        let reservedNamesList=try!(root.getList("lan.reservedNames"));
+
+                        let mut reservedNames=Vec::new();
+
+                        for name in reservedNamesList.iter() {
+                            reservedNames.push(try!(name.getString()).clone());
+                        }
+
+                        reservedNames
+                    },
+                    lan_discoveryEnabled:try!(root.getStringAs::<bool>("lan.discoveryEnabled")),
+                    lan_discoveryPort:try!(root.getStringAs::<u16>("lan.discoveryPort")),
+                    lan_discoveryInterface:try!(readIPv4(&root, "lan.discoveryInterface")),
+                    lan_discoveryAddress:try!(readIPv4(&root, "lan.discoveryAddress")),
+                    lan_discoveryInterval:try!(root.getStringAs::<u64>("lan.discoveryInterval")),
+                    queue_length:try!(root.getStringAs::<usize>("queue.length")),
+                    queue_reservedSlots:try!(root.getStringAs::<usize>("queue.reservedSlots")),
+                    queue_positionUpdateInterval:{
+                        let updateInterval=try!(root.getStringAs::<i64>("queue.positionUpdateInterval"));
+
+                        if updateInterval<=0 {
+                            return Err(String::from("queue.positionUpdateInterval must be more than 0"));
+                        }
+
+                        updateInterval
+                    },
+                    shutdown_countdown:try!(root.getStringAs::<u64>("shutdown.countdown")),
+                    shutdown_warnings:{
+                        let warningsList=try!(root.getList("shutdown.warnings"));
+
+                        let mut warnings=Vec::new();
+
+                        for warning in warningsList.iter() {
+                            match try!(warning.getString()).parse::<u64>() {
+                                Ok ( w ) => warnings.push(w),
+                                Err( _ ) => return Err(String::from("shutdown.warnings must contain numbers of seconds")),
+                            }
+                        }
+
+                        //от большего к меньшему, так их и отправляем
+                        warnings.sort_by(|a, b| b.cmp(a));
+
+                        warnings
+                    },
+                    shutdown_disconnectDelay:{
+                        let disconnectDelay=try!(root.getStringAs::<i64>("shutdown.disconnectDelay"));
+
+                        if disconnectDelay<=0 {
+                            return Err(String::from("shutdown.disconnectDelay must be more than 0"));
+                        }
+
+                        disconnectDelay
+                    },
                     loadMap:try!(root.getString("load map")).clone(),
                     generateMap:try!(root.getString("generate map")).clone(),
                 }
@@ -81,4 +266,25 @@ impl ServerConfig{
 
         Ok(serverConfig)
     }
+
+    ///Name of loaded map or description of generated one, for server lists
+    pub fn getMapDescription(&self) -> String {
+        if self.loadMap.len()>0 {
+            self.loadMap.clone()
+        }else{
+            self.generateMap.clone()
+        }
+    }
+}
+
+fn readRateLimit(root:&Map, name:&str) -> Result<RateLimit, String> {
+    let map=try!(root.getMap(name));
+
+    RateLimit::read(map).or_else(|e| Err(format!("{} : {}", name, e)))
+}
+
+fn readIPv4(root:&Map, name:&str) -> Result<Ipv4Addr, String> {
+    let address=try!(root.getString(name));
+
+    Ipv4Addr::from_str(address).or_else(|_| Err(format!("{} : \"{}\" is not IPv4 address", name, address)))
 }
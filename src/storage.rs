@@ -1,17 +1,61 @@
 use std::sync::{Mutex,RwLock,Arc,Barrier,Weak};
 
 use appData::AppData;
+use accounts::AccountStore;
+use banList::BanList;
+use roles::Roles;
+use worldStore::WorldStore;
 
 pub struct Storage{
     pub appData:Weak<AppData>,
+    pub accounts:AccountStore,
+    pub banList:BanList,
+    pub roles:Roles,
+    pub world:WorldStore,
 }
 
 impl Storage{
     pub fn initialize( appData:Arc<AppData> ) -> bool{
         appData.log.print( format!("[INFO] Initializing Storage") );
 
+        let accounts=match AccountStore::load() {
+            Ok ( accounts ) => accounts,
+            Err( e ) => {
+                appData.log.print( format!("[ERROR] Can not load accounts : {}", e) );
+                return false;
+            },
+        };
+
+        let banList=match BanList::load() {
+            Ok ( banList ) => banList,
+            Err( e ) => {
+                appData.log.print( format!("[ERROR] Can not load ban list : {}", e) );
+                return false;
+            },
+        };
+
+        let roles=match Roles::load() {
+            Ok ( roles ) => roles,
+            Err( e ) => {
+                appData.log.print( format!("[ERROR] Can not load roles : {}", e) );
+                return false;
+            },
+        };
+
+        let world=match WorldStore::load() {
+            Ok ( world ) => world,
+            Err( e ) => {
+                appData.log.print( format!("[ERROR] Can not load world : {}", e) );
+                return false;
+            },
+        };
+
         let storage=Storage{
             appData:Arc::downgrade(&appData),
+            accounts:accounts,
+            banList:banList,
+            roles:roles,
+            world:world,
         };
 
         let storage=Arc::new(storage);
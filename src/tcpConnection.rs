@@ -9,6 +9,7 @@ use byteorder::{ByteOrder, BigEndian};
 
 use mio::*;
 use mio::tcp::*;
+use std::net::SocketAddr;
 
 use std::collections::VecDeque;
 
@@ -16,10 +17,18 @@ use time::Timespec;
 use time::get_time;
 //use std::time::Duration;
 
-use server::{Server, DisconnectionReason, DisconnectionSource};
-use tcpServer::TCPServer;
+use server::{Server, DisconnectionReason, DisconnectionSource, getServerTime};
+use tcpServer::TCPServerMessage;
+use udpServer::UDPServerMessage;
+use game::GameMessage;
 
-use packet::{ServerToClientTCPPacket, ClientToServerTCPPacket};
+use std::mem;
+use rand::random;
+
+use packet::{ServerToClientTCPPacket, ClientToServerTCPPacket, DisconnectionCode};
+use rateLimiter::{RateLimit, RateLimiter};
+use version::{Version, PROTOCOL_VERSION, GAME_VERSION};
+use roles::Role;
 
 /*
 причины disconnect:
@@ -41,6 +50,7 @@ take позволяет ограничить кол-во читаемых бай
 match self.socket.write(&message[..]) { а не рвет ли он сообщения?! придется тогда делать writeContinuation
 */
 
+const STATE_WAITING_VERSIONS_TIMEOUT: usize = 10;
 const STATE_WAITING_SESSIONID_TIMEOUT: usize = 10;
 const STATE_LOADING_PLAYER_DATA_FROM_MASTER_SERVER_TIMEOUT: usize = 10;
 const STATE_LOGIN_OR_REGISTER_TIMEOUT: usize = 10;
@@ -51,16 +61,43 @@ const STATE_INITIALIZING_UDP_CONNECTION_TIMEOUT: usize = 5;
 const MESSAGE_LIMIT_NOT_PLAYING: usize = 16*1024;//256;
 const MESSAGE_LIMIT_PLAYING: usize = 16*1024;
 
-const MASTERSERVER_ADDRESS:&'static str = "89.110.48.1:1941";
-
 #[derive(PartialEq, Eq, Clone)]
 pub enum TCPConnectionStage{
     Disconnecting( DisconnectionReason ),
+    WaitingVersions(i64),
     WaitingSessionID(i64),
-    LoadingPlayerDataFromMasterServer(i64),
+    LoadingPlayerDataFromMasterServer(i64, usize, String, String), //timeout, index and address of master server, sessionID of client
     LoginOrRegister(i64, usize),
+    Queued(QueueTicket), //server is full, TCPServer promotes connection to UDPConnectionInitialization, when slot frees up
     UDPConnectionInitialization(i64, usize, String),
     Playing,
+    Detached(i64), //socket is lost, player and UDP connection wait for resume until timeout
+}
+
+#[derive(PartialEq, Eq, Clone)]
+pub struct QueueTicket{
+    pub enqueueTime:u64, //ms, queue is ordered by it
+    pub userID:usize,
+    pub userName:String,
+    pub isAdmin:bool, //may take reserved slots
+    pub position:usize, //the last position sent to client, 0 - not sent yet
+    pub nextPositionUpdateTime:i64,
+}
+
+impl TCPConnectionStage{
+    pub fn print(&self) -> &'static str {
+        match *self {
+            TCPConnectionStage::Disconnecting( _ ) => "disconnecting",
+            TCPConnectionStage::WaitingVersions( _ ) => "waiting versions",
+            TCPConnectionStage::WaitingSessionID( _ ) => "waiting sessionID",
+            TCPConnectionStage::LoadingPlayerDataFromMasterServer( _, _, _, _ ) => "loading player data",
+            TCPConnectionStage::LoginOrRegister( _, _ ) => "login or register",
+            TCPConnectionStage::Queued( _ ) => "queued",
+            TCPConnectionStage::UDPConnectionInitialization( _, _, _ ) => "UDP connection initialization",
+            TCPConnectionStage::Playing => "playing",
+            TCPConnectionStage::Detached( _ ) => "detached",
+        }
+    }
 }
 
 enum ReadingState{
@@ -70,10 +107,13 @@ enum ReadingState{
 
 pub struct TCPConnection{
     pub token: Token,
+    pub address: SocketAddr,
     server: Arc<Server>,
     pub shouldReset: bool,
     pub isActive:bool,
     pub shouldReregister:bool,
+    pub hasUDPConnection:bool,
+    isRegistered:bool,
 
     socket: TcpStream,
 
@@ -87,6 +127,8 @@ pub struct TCPConnection{
     needsToWrite:usize,
 
     pub stage:TCPConnectionStage,
+    pub rateLimiter:RateLimiter,
+    pub resumeToken:u64,
 }
 
 pub enum ReadResult{
@@ -97,13 +139,16 @@ pub enum ReadResult{
 }
 
 impl TCPConnection{
-    pub fn new(socket: TcpStream, token: Token, server:Arc<Server>) -> TCPConnection {
+    pub fn new(socket: TcpStream, address: SocketAddr, token: Token, server:Arc<Server>, rateLimit:&RateLimit) -> TCPConnection {
         TCPConnection {
             token: token,
+            address: address,
             server: server,
             shouldReset: false,
             isActive:true,
             shouldReregister:false,
+            hasUDPConnection:false,
+            isRegistered:false,
 
             socket: socket,
 
@@ -116,7 +161,9 @@ impl TCPConnection{
             writeBuffer:None,
             needsToWrite:0,
 
-            stage:TCPConnectionStage::WaitingSessionID( get_time().sec+STATE_WAITING_SESSIONID_TIMEOUT as i64 ),
+            stage:TCPConnectionStage::WaitingVersions( get_time().sec+STATE_WAITING_VERSIONS_TIMEOUT as i64 ),
+            rateLimiter:RateLimiter::new(rateLimit),
+            resumeToken:0,
         }
     }
 
@@ -251,7 +298,16 @@ impl TCPConnection{
         Ok(())
     }
 
+    pub fn hasMessagesToSend(&self) -> bool {
+        self.writeBuffer.is_some() || self.sendQueue.len()>0
+    }
+
     pub fn sendMessage(&mut self, msg:Vec<u8>){
+        match self.stage {
+            TCPConnectionStage::Detached( _ ) => return, //сокета нет, а после resume клиент все равно синхронизируется
+            _ => {},
+        }
+
         println!("send: {}",msg.len());
 
         self.sendQueue.push_front(msg);
@@ -269,14 +325,18 @@ impl TCPConnection{
     pub fn register(&mut self, poll: &mut Poll) -> Result<(), &'static str> {
         let interest=Ready::hup() | Ready::error() | Ready::readable();
 
-        poll.register(
+        try!(poll.register(
             &self.socket,
             self.token,
             interest,
             PollOpt::edge() | PollOpt::oneshot()
         ).or_else(|e|
             Err("Can not register connection")
-        )
+        ));
+
+        self.isRegistered=true;
+
+        Ok(())
     }
 
     /// Re-register interest in read events with poll.
@@ -316,27 +376,37 @@ impl TCPConnection{
         }
 
         match self.stage {
+            TCPConnectionStage::WaitingVersions( timeout ) => {
+                if get_time().sec>=timeout {
+                    self.disconnect( DisconnectionReason::ServerError( DisconnectionCode::Timeout, String::from("Expectation versions timeout")) );
+                }
+            },
             TCPConnectionStage::WaitingSessionID( timeout ) => {
                 if get_time().sec>=timeout {
-                    self.disconnect( DisconnectionReason::ServerError( String::from("Expectation Session ID timeout")) );
+                    self.disconnect( DisconnectionReason::ServerError( DisconnectionCode::Timeout, String::from("Expectation Session ID timeout")) );
                 }
             },
-            TCPConnectionStage::LoadingPlayerDataFromMasterServer( timeout ) => {
-                if get_time().sec>=timeout {//не удалось подключиться к главному серверу - предложим зарегаться или залогиниться
-                    self.stage=TCPConnectionStage::LoginOrRegister( get_time().sec + STATE_LOGIN_OR_REGISTER_TIMEOUT as i64, 0 );
-                    self.sendMessage( ServerToClientTCPPacket::LoginOrRegister.pack() );
+            TCPConnectionStage::LoadingPlayerDataFromMasterServer( timeout, _, _, _ ) => {
+                if get_time().sec>=timeout {//главный сервер не ответил - попробуем следующий, а если их нет, предложим зарегаться или залогиниться
+                    self.skipMasterServer();
                 }
             },
             TCPConnectionStage::LoginOrRegister( timeout, attemptsNumber ) => {
                 if get_time().sec>=timeout {
-                    self.disconnect( DisconnectionReason::ServerError( String::from("Expectation Login timeout")) );
+                    self.disconnect( DisconnectionReason::ServerError( DisconnectionCode::Timeout, String::from("Expectation Login timeout")) );
                 }
             },
             TCPConnectionStage::UDPConnectionInitialization( timeout, _ , _ ) => {
                 if get_time().sec>=timeout {
-                    self.disconnect( DisconnectionReason::ServerError( String::from("Initialization UDP Connection timeout")) );
+                    self.disconnect( DisconnectionReason::ServerError( DisconnectionCode::Timeout, String::from("Initialization UDP Connection timeout")) );
                 }
             }
+            TCPConnectionStage::Detached( timeout ) => {
+                if get_time().sec>=timeout {
+                    self.disconnect( DisconnectionReason::ServerError( DisconnectionCode::SessionExpired, String::from("Session has not been resumed in time")) );
+                }
+            },
+            TCPConnectionStage::Queued( _ ) => {}, //клиент ждет сколько угодно, пока сам не отключится
             TCPConnectionStage::Playing => {},
             _=>{},
         }
@@ -362,11 +432,16 @@ impl TCPConnection{
     pub fn _disconnect(&mut self, reason:DisconnectionReason){
         println!("disconnect!");
 
+        let isDetached=match self.stage {
+            TCPConnectionStage::Detached( _ ) => true,
+            _ => false,
+        };
+
         match reason{
             DisconnectionReason::Hup =>
                 self.shouldReset=true,
             DisconnectionReason::ServerShutdown =>
-                self.sendAbschiedMessage( ServerToClientTCPPacket::ServerShutdown.pack() ),
+                self.sendAbschiedMessage( ServerToClientTCPPacket::Disconnected( reason.code(), String::new() ).pack() ),
             DisconnectionReason::FatalError( msg ) => {
                 //match source{
                 //    DisconnectionSource::TCP =>
@@ -377,12 +452,12 @@ impl TCPConnection{
             },
             DisconnectionReason::ClientDesire( ref msg ) =>
                 self.shouldReset=true,
-            DisconnectionReason::ServerDesire( ref msg ) =>
-                self.sendAbschiedMessage( ServerToClientTCPPacket::ServerDesire( msg.clone() ).pack() ),
+            DisconnectionReason::ServerDesire( code, ref msg ) =>
+                self.sendAbschiedMessage( ServerToClientTCPPacket::Disconnected( code, msg.clone() ).pack() ),
             DisconnectionReason::ClientError( ref msg ) =>
                 self.shouldReset=true,
-            DisconnectionReason::ServerError( ref msg ) =>
-                self.sendAbschiedMessage( ServerToClientTCPPacket::ServerError( msg.clone() ).pack() ),
+            DisconnectionReason::ServerError( code, ref msg ) =>
+                self.sendAbschiedMessage( ServerToClientTCPPacket::Disconnected( code, msg.clone() ).pack() ),
         }
 
         self.server.appData.upgrade().unwrap().log.print(
@@ -395,17 +470,22 @@ impl TCPConnection{
                     format!("[ERROR] Disconnecting : fatal error : {}",msg),
                 DisconnectionReason::ClientDesire ( ref msg ) =>
                     format!("[INFO] Disconnecting : client desire : {}",msg),
-                DisconnectionReason::ServerDesire ( ref msg ) =>
-                    format!("[INFO] Disconnecting : server desire : {}",msg),
+                DisconnectionReason::ServerDesire ( code, ref msg ) =>
+                    format!("[INFO] Disconnecting : server desire : {} : {}",code.print(),msg),
                 DisconnectionReason::ClientError ( ref msg ) =>
                     format!("[ERROR] Disconnecting : client error : {}",msg),
-                DisconnectionReason::ServerError( ref msg ) =>
-                    format!("[ERROR] Disconnecting : server error : {}",msg),
+                DisconnectionReason::ServerError( code, ref msg ) =>
+                    format!("[ERROR] Disconnecting : server error : {} : {}",code.print(),msg),
             }
         );
 
         self.stage=TCPConnectionStage::Disconnecting( reason.clone() );
         self.isActive=false;
+
+        if isDetached { //сокета уже нет, прощаться не с кем
+            self.sendQueue.clear();
+            self.shouldReset=true;
+        }
     }
 
     pub fn disconnect(&mut self, reason:DisconnectionReason){
@@ -413,22 +493,120 @@ impl TCPConnection{
             return ;
         }
 
+        //соединение просто оборвалось - игрок и UDP соединение подождут, пока клиент переподключится
+        match self.stage {
+            TCPConnectionStage::Playing => {
+                match reason {
+                    DisconnectionReason::Hup | DisconnectionReason::FatalError( _ ) => {
+                        self.detach();
+                        return;
+                    },
+                    _ => {},
+                }
+            },
+            _ => {},
+        }
+
         self._disconnect(reason.clone());
 
         let sessionID:usize=usize::from(self.token);
 
-        self.server.tryDisconnectUDPConnection( sessionID, reason.clone() );
-        self.server.tryDisconnectPlayer( sessionID, reason );
+        self.server.disconnect( sessionID, reason, DisconnectionSource::TCP );
+    }
+
+    fn detach(&mut self) {
+        let appData=self.server.appData.upgrade().unwrap();
+        let sessionID:usize=usize::from(self.token);
+
+        appData.log.print( format!("[INFO] Connection {} has been lost, waiting for resume", sessionID) );
+
+        self.stage=TCPConnectionStage::Detached( get_time().sec + appData.serverConfig.server_resumeGraceTime as i64 );
+        self.shouldReregister=false;
+
+        self.sendQueue.clear();
+        self.writeBuffer=None;
+        self.needsToWrite=0;
+
+        self.server.sendToUDPServer( UDPServerMessage::SetDetached(sessionID, true) );
+        self.server.sendToGame( GameMessage::PlayerDetached(sessionID) );
+    }
+
+    ///Takes socket of new connection, which has sent correct ClientToServerTCPPacket::Resume. The new connection gets the old socket and should be removed
+    pub fn resume(&mut self, newConnection:&mut TCPConnection, poll: &mut Poll) -> Result<(), &'static str> {
+        self.deregister(poll);
+        newConnection.deregister(poll);
+
+        mem::swap(&mut self.socket, &mut newConnection.socket);
+        mem::swap(&mut self.address, &mut newConnection.address);
+
+        self.needsToRead=4;
+        self.readingState=ReadingState::ReadingLength([0;4]);
+        self.writeBuffer=None;
+        self.needsToWrite=0;
+        self.sendQueue.clear();
+
+        self.stage=TCPConnectionStage::Playing;
+        self.resumeToken=random::<u64>();
+
+        try!(self.register(poll));
+
+        let sessionID:usize=usize::from(self.token);
+
+        self.sendMessage( ServerToClientTCPPacket::SessionResumed(self.resumeToken).pack() );
+
+        self.server.sendToUDPServer( UDPServerMessage::SetDetached(sessionID, false) );
+        self.server.sendToGame( GameMessage::PlayerResumed(sessionID) );
+
+        Ok(())
     }
 
     pub fn deregister(&mut self, poll: &mut Poll) {
+        if !self.isRegistered {
+            return;
+        }
+
         poll.deregister(
             &self.socket
         );
+
+        self.isRegistered=false;
     }
 
     pub fn processPacket(&mut self, packet:&ClientToServerTCPPacket) -> Result<(), String> {
+        match self.stage {
+            TCPConnectionStage::WaitingVersions(_) => {
+                match *packet {
+                    ClientToServerTCPPacket::Versions( _, _ ) => {},
+                    _ => return Err( String::from("expected ClientToServerTCPPacket::Versions") ),
+                }
+            },
+            _ => {},
+        }
+
         match *packet{
+            ClientToServerTCPPacket::Versions( ref protocolVersion, ref gameVersion ) => {
+                match self.stage {
+                    TCPConnectionStage::WaitingVersions(_) => {},
+                    _ => return Err( String::from("unexpected ClientToServerTCPPacket::Versions") ),
+                }
+
+                let requiredProtocolVersion=Version::fromBytes(PROTOCOL_VERSION);
+                let requiredGameVersion=Version::fromBytes(GAME_VERSION);
+
+                if *protocolVersion!=requiredProtocolVersion || *gameVersion!=requiredGameVersion {
+                    self.reject(
+                        DisconnectionCode::VersionMismatch,
+                        ServerToClientTCPPacket::IncompatibleVersions(requiredProtocolVersion, requiredGameVersion),
+                        format!("Incompatible client : protocol {} game {}, required protocol {} game {}",
+                            protocolVersion.print(), gameVersion.print(), requiredProtocolVersion.print(), requiredGameVersion.print()
+                        )
+                    );
+
+                    return Ok(());
+                }
+
+                self.stage=TCPConnectionStage::WaitingSessionID( get_time().sec + STATE_WAITING_SESSIONID_TIMEOUT as i64 );
+            },
             ClientToServerTCPPacket::SessionID( ref sessionID ) => {
                 match self.stage {
                     TCPConnectionStage::WaitingSessionID(_) => {},
@@ -436,52 +614,7 @@ impl TCPConnection{
                 }
 
                 if sessionID.len()>0 { //точнее = 256 байт в base64
-                    self.stage=TCPConnectionStage::LoadingPlayerDataFromMasterServer( get_time().sec + STATE_LOADING_PLAYER_DATA_FROM_MASTER_SERVER_TIMEOUT as i64 );
-
-                    let appData=self.server.appData.upgrade().unwrap();
-
-                    let request=format!("GET /getUserIDAndName_sessionID={} HTTP/1.1\r\nHost: {}\r\n\r\n", sessionID, MASTERSERVER_ADDRESS).into_bytes();
-                    let token=self.token;
-
-                    appData.clone().getHTTPRequesterAnd(move |httpRequester| httpRequester.addRequest(
-                        MASTERSERVER_ADDRESS,
-                        request,
-                        STATE_LOADING_PLAYER_DATA_FROM_MASTER_SERVER_TIMEOUT,
-
-                        move |responseCode:usize, buffer:&[u8] | {
-
-                            if responseCode==200 || responseCode==304  {
-                                let response=String::from_utf8_lossy(buffer);
-
-                                if response.starts_with("Error:") {
-                                    let server=appData.getServerAnd(move | server | {
-                                        server.getSafeTCPConnectionAnd(token, | connection | {
-                                            connection.disconnect(DisconnectionReason::ServerError(
-                                                String::from("invalid SessionID, try to connect to server from server list again")
-                                            ));
-                                        });
-                                    });
-                                }else{
-                                    let server=appData.getServerAnd(move | server | {
-                                        server.getSafeTCPConnectionAnd(token, |connection| {
-                                            match connection.initializeUDPConnection(&*response) {
-                                                Ok ( _ ) => {},
-                                                Err( e ) => connection.disconnect( DisconnectionReason::ServerError(e)),
-                                            }
-                                        });
-                                    });
-                                }
-                            }else{
-                                println!("code: {}",responseCode);
-                                let server=appData.getServerAnd(move | server | {
-                                    server.getSafeTCPConnectionAnd(token, |connection| {
-                                        connection.stage=TCPConnectionStage::LoginOrRegister( get_time().sec + STATE_LOGIN_OR_REGISTER_TIMEOUT as i64, 0 );
-                                        connection.sendMessage( ServerToClientTCPPacket::LoginOrRegister.pack() );
-                                    });
-                                });
-                            }
-                        }
-                    ));
+                    self.loadPlayerData(sessionID.clone(), 0);
                 }else{
                     return Err( String::from("no sessionID") );
 
@@ -490,13 +623,117 @@ impl TCPConnection{
                     //self.sendMessage( ServerToClientTCPPacket::LoginOrRegister.pack() );
                 }
             },
+            ClientToServerTCPPacket::Login( ref userName, ref password ) | ClientToServerTCPPacket::Register( ref userName, ref password ) => {
+                let attemptsNumber=match self.stage {
+                    TCPConnectionStage::LoginOrRegister( _, attemptsNumber ) => attemptsNumber,
+                    _ => return Err( String::from("unexpected ClientToServerTCPPacket::Login or Register") ),
+                };
+
+                let appData=self.server.appData.upgrade().unwrap();
+
+                let result=match *packet {
+                    ClientToServerTCPPacket::Register( _, _ ) =>
+                        appData.getStorageAnd(|storage| storage.accounts.register(userName, password)),
+                    _ =>
+                        appData.getStorageAnd(|storage| storage.accounts.login(userName, password)),
+                };
+
+                match result {
+                    Ok ( userID ) => self.allowUDPConnection(userID, userName.clone()),
+                    Err( e ) => {
+                        let attemptsNumber=attemptsNumber+1;
+
+                        if attemptsNumber>=STATE_LOGIN_OR_REGISTER_ATTEMPTS_LIMIT {
+                            self.disconnect( DisconnectionReason::ServerError( DisconnectionCode::AuthFailed, String::from("Login or register attempts limit has been exceeded") ) );
+                            return Ok(());
+                        }
+
+                        self.stage=TCPConnectionStage::LoginOrRegister( get_time().sec + STATE_LOGIN_OR_REGISTER_TIMEOUT as i64, attemptsNumber );
+                        self.sendMessage( ServerToClientTCPPacket::LoginOrRegisterFailed( e, STATE_LOGIN_OR_REGISTER_ATTEMPTS_LIMIT-attemptsNumber ).pack() );
+                    },
+                }
+            },
             _ => {},
         }
 
         Ok(())
     }
 
-    fn initializeUDPConnection(&mut self, response:&str ) -> Result<(), String> {
+    ///Disconnects with code, but before ServerToClientTCPPacket::Disconnected client gets packet, which explains the reason in details
+    pub fn reject(&mut self, code:DisconnectionCode, packet:ServerToClientTCPPacket, reason:String){
+        self.disconnect( DisconnectionReason::ServerError(code, reason) );
+        self.sendQueue.push_back( packet.pack() ); //отправится раньше прощального сообщения
+    }
+
+    ///Requests userID and userName from the first alive master server, beginning with fromIndex. If there is no one, offers LoginOrRegister
+    pub fn loadPlayerData(&mut self, sessionID:String, fromIndex:usize){
+        let appData=self.server.appData.upgrade().unwrap();
+
+        let (masterServerIndex, address)=match appData.masterServers.selectEndpoint(fromIndex) {
+            Some( endpoint ) => endpoint,
+            None => {
+                self.offerLoginOrRegister();
+                return;
+            },
+        };
+
+        let request=format!("GET /getUserIDAndName_sessionID={} HTTP/1.1\r\nHost: {}\r\n\r\n", sessionID, address).into_bytes();
+        let token=self.token;
+        let masterServerAddress=address.clone();
+
+        self.stage=TCPConnectionStage::LoadingPlayerDataFromMasterServer( get_time().sec + STATE_LOADING_PLAYER_DATA_FROM_MASTER_SERVER_TIMEOUT as i64, masterServerIndex, address.clone(), sessionID );
+
+        appData.clone().getHTTPRequesterAnd(move |httpRequester| httpRequester.addRequest(
+            &address,
+            request,
+            STATE_LOADING_PLAYER_DATA_FROM_MASTER_SERVER_TIMEOUT,
+
+            move |responseCode:usize, buffer:&[u8] | {
+                //вызывается потоком HTTPRequester, поэтому соединение не трогаем, а отправляем сообщение TCPServer
+                let sessionID=usize::from(token);
+
+                let message=if responseCode==200 || responseCode==304  {
+                    appData.masterServers.markAlive(&masterServerAddress);
+
+                    let response=String::from_utf8_lossy(buffer).into_owned();
+
+                    if response.starts_with("Error:") {
+                        TCPServerMessage::Disconnect(sessionID, DisconnectionReason::ServerError(
+                            DisconnectionCode::InvalidSession, String::from("invalid SessionID, try to connect to server from server list again")
+                        ))
+                    }else{
+                        TCPServerMessage::PlayerDataLoaded(sessionID, response)
+                    }
+                }else if responseCode>=500 && responseCode<600 {
+                    appData.masterServers.markDead(&masterServerAddress);
+                    TCPServerMessage::MasterServerFailed(sessionID, masterServerIndex)
+                }else{
+                    appData.log.print( format!("[WARNING] Master server has answered {} to SessionID of connection {}", responseCode, sessionID) );
+                    TCPServerMessage::MasterServerUnavailable(sessionID)
+                };
+
+                appData.getServerAnd(move | server | server.sendToTCPServer(message));
+            }
+        ));
+    }
+
+    fn skipMasterServer(&mut self){
+        let (masterServerIndex, masterServerAddress, sessionID)=match self.stage {
+            TCPConnectionStage::LoadingPlayerDataFromMasterServer( _, masterServerIndex, ref masterServerAddress, ref sessionID ) =>
+                (masterServerIndex, masterServerAddress.clone(), sessionID.clone()),
+            _ => return,
+        };
+
+        self.server.appData.upgrade().unwrap().masterServers.markDead(&masterServerAddress);
+        self.loadPlayerData(sessionID, masterServerIndex+1);
+    }
+
+    pub fn offerLoginOrRegister(&mut self){
+        self.stage=TCPConnectionStage::LoginOrRegister( get_time().sec + STATE_LOGIN_OR_REGISTER_TIMEOUT as i64, 0 );
+        self.sendMessage( ServerToClientTCPPacket::LoginOrRegister.pack() );
+    }
+
+    pub fn initializeUDPConnection(&mut self, response:&str ) -> Result<(), String> {
         use description;
 
         let (userID, userName)=try!(
@@ -508,12 +745,76 @@ impl TCPConnection{
             })
         );
 
-        self.stage=TCPConnectionStage::UDPConnectionInitialization( get_time().sec + STATE_INITIALIZING_UDP_CONNECTION_TIMEOUT as i64, userID, userName );
+        self.allowUDPConnection(userID, userName.clone());
+
+        Ok(())
+    }
+
+    ///Player is known(by master server or local account), now he waits for a free slot, see TCPServer::processQueue
+    pub fn allowUDPConnection(&mut self, userID:usize, userName:String) {
+        let appData=self.server.appData.upgrade().unwrap();
+
+        let role=appData.getStorageAnd(|storage| storage.roles.getRole(userID));
+
+        //IP проверен при accept, а теперь известен и юзер
+        match appData.getStorageAnd(|storage| storage.banList.checkUser(userID, &userName)) {
+            Some( ban ) => {
+                self.disconnect( DisconnectionReason::ServerDesire( DisconnectionCode::Banned, ban.print() ) );
+                return;
+            },
+            None => {},
+        }
+
+        //если место есть, TCPServer переведет соединение в UDPConnectionInitialization в том же цикле, и клиент не узнает об очереди
+        self.stage=TCPConnectionStage::Queued(
+            QueueTicket{
+                enqueueTime:getServerTime(),
+                userID:userID,
+                userName:userName,
+                isAdmin:role==Role::Admin,
+                position:0,
+                nextPositionUpdateTime:0,
+            }
+        );
+    }
+
+    ///Slot is free, now client should initialize UDP connection
+    pub fn promoteFromQueue(&mut self) {
+        let (userID, userName)=match self.stage {
+            TCPConnectionStage::Queued( ref ticket ) => (ticket.userID, ticket.userName.clone()),
+            _ => return,
+        };
+
+        self.stage=TCPConnectionStage::UDPConnectionInitialization( get_time().sec + STATE_INITIALIZING_UDP_CONNECTION_TIMEOUT as i64, userID, userName.clone() );
 
         let sessionID:usize=usize::from(self.token);
+
+        //UDPServer будет ждать от клиента ClientToServerUDPPacket::Initialization
+        self.server.sendToUDPServer( UDPServerMessage::AllowConnection(sessionID, userID, userName) );
         self.sendMessage( ServerToClientTCPPacket::InitializeUDPConnection(sessionID).pack() );
+    }
 
-        Ok(())
+    ///Sends position, if it has changed or client has not been informed for updateInterval
+    pub fn updateQueuePosition(&mut self, position:usize, queueLength:usize, updateInterval:i64) {
+        let time=get_time().sec;
+
+        let shouldSend=match self.stage {
+            TCPConnectionStage::Queued( ref mut ticket ) => {
+                if ticket.position!=position || time>=ticket.nextPositionUpdateTime {
+                    ticket.position=position;
+                    ticket.nextPositionUpdateTime=time+updateInterval;
+
+                    true
+                }else{
+                    false
+                }
+            },
+            _ => false,
+        };
+
+        if shouldSend {
+            self.sendMessage( ServerToClientTCPPacket::QueuePosition(position, queueLength).pack() );
+        }
     }
 
 
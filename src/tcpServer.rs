@@ -1,28 +1,46 @@
 use std::thread;
-use std::thread::JoinHandle;
-use std::sync::{Mutex,Arc,RwLock,Weak};
+use std::sync::Arc;
 
 use std::io::{self, ErrorKind};
-use std::rc::Rc;
 
 use mio::*;
 use mio::tcp::*;
+use mio::channel::Receiver;
 use slab::Slab;
-use std::net::SocketAddr;
+use std::net::{SocketAddr, IpAddr};
+use std::collections::HashMap;
 
 use time::{get_time};
 use std::time::Duration;
 
 use appData::AppData;
+use serverConfig::ServerConfig;
+use rateLimiter::{RateLimit, TokenBucket};
 use server::{Server, DisconnectionReason, DisconnectionSource};
 use server::ServerState;
 
-use tcpConnection::{TCPConnection, ReadResult};
+use tcpConnection::{TCPConnection, ReadResult, TCPConnectionStage};
+use lanAuth::LANAuth;
+use game::GameMessage;
+use command::CommandReply;
 
-use packet::{ServerToClientTCPPacket, ClientToServerTCPPacket};
+use packet::{ServerToClientTCPPacket, ClientToServerTCPPacket, DisconnectionCode};
+
+use rand::random;
 
 const  ACTIVITY_CONNECTION_LOST_DELAY: i64 = 10;
-const  ACTIVITY_DISCONNECT_DELAY: i64 = 2;
+
+pub enum TCPServerMessage{
+    Send( usize, Vec<u8> ),
+    Disconnect( usize, DisconnectionReason ),
+    PlayerDataLoaded( usize, String ), //response of master server
+    MasterServerUnavailable( usize ),
+    MasterServerFailed( usize, usize ), //sessionID, index of master server - next one should be tried
+    UDPConnectionInitialized( usize, u64 ), //sessionID, session of UDP connection
+    UDPConnectionRemoved( usize ),
+    ListConnections( CommandReply ),
+    SetAccepting( bool ), //new clients are not accepted, while shutdown is scheduled
+}
 
 pub struct TCPServer{
     pub appData:Arc<AppData>,
@@ -32,26 +50,59 @@ pub struct TCPServer{
     pub token: Token, // token of our server. we keep track of it here instead of doing `const SERVER = Token(0)`.
     pub events: Events, // a list of events to process
     pub tickTime: i64,
+
+    connections: Slab<TCPConnection, Token>,
+    receiver: Receiver<TCPServerMessage>,
+    receiverToken: Token,
+
+    connectionsPerIP: HashMap<IpAddr, usize>,
+    acceptLimiters: HashMap<IpAddr, (TokenBucket, bool)>, //bucket, is exceeding reported
+
+    lanAuth: Option<LANAuth>, //only in LAN mode
+    bansVersion: usize, //when version of ban list changes, connections are checked again
+    isAccepting: bool,
 }
 
 impl TCPServer{
+    pub fn new(appData:Arc<AppData>, server:Arc<Server>, addr:&SocketAddr, receiver:Receiver<TCPServerMessage>) -> Result<TCPServer, String> {
+        let connectionsLimit=appData.serverConfig.server_connectionsLimit;
+
+        let lanAuth=if appData.serverConfig.lan_enabled {
+            appData.log.print(format!("[INFO] Server is in LAN mode, master server is not used"));
+            Some( LANAuth::new(&appData.serverConfig) )
+        }else{
+            None
+        };
+
+        let bansVersion=appData.getStorageAnd(|storage| storage.banList.getVersion());
+
+        Ok(TCPServer{
+            appData:appData,
+            server:server,
+            listener:try!(TcpListener::bind(addr).or( Err(format!("Can not create TCP socket with address : {}", addr)) )),
+            poll:try!(Poll::new().or( Err(format!("Can not create TCP event poll")) )),
+            token:Token(10_000_000),
+            events:Events::with_capacity(connectionsLimit*8),
+            tickTime:get_time().sec,
+
+            connections:Slab::with_capacity(connectionsLimit),
+            receiver:receiver,
+            receiverToken:Token(10_000_001),
+
+            connectionsPerIP:HashMap::new(),
+            acceptLimiters:HashMap::new(),
+
+            lanAuth:lanAuth,
+            bansVersion:bansVersion,
+            isAccepting:true,
+        })
+    }
+
     pub fn process(&mut self) -> Result<(), &'static str>{
         try!(self.register().or(Err( "Can not register server poll" ) ) );
 
-        {
-            let mut serverStateGuard=self.server.state.write().unwrap();
-
-            if *serverStateGuard==ServerState::Initialization(0) {
-                (*serverStateGuard)=ServerState::Initialization(1);
-            }else if *serverStateGuard==ServerState::Initialization(1) {
-                *serverStateGuard=ServerState::Processing;
-            }else if *serverStateGuard!=ServerState::Processing {//Disconnect кем-то другим
-                return Ok(());
-            }
-        }
-
-        while match *self.server.state.read().unwrap() { ServerState::Initialization(_) => true, _=>false} {
-            thread::sleep_ms(10);
+        if !self.server.onThreadInitialized() {
+            return Ok(());
         }
 
         self.appData.log.print(format!("[INFO] TCP server is ready"));
@@ -65,7 +116,9 @@ impl TCPServer{
                 try!(self.processEvent(event.token(), event.kind()));
             }
 
-            self.disconnectTCPConnectionsFromList();
+            self.processServerMessages();
+
+            self.processQueue();
 
             self.reregisterConnections();
 
@@ -75,29 +128,179 @@ impl TCPServer{
         Ok(())
     }
 
-    fn disconnectTCPConnectionsFromList(&mut self){
-        let disconnectTCPConnectionsListGuard=self.server.disconnectTCPConnectionsList.lock().unwrap();
-        let tcpConnectionsGuard=self.server.tcpConnections.read().unwrap();
+    fn processServerMessages(&mut self){
+        loop{
+            let message=match self.receiver.try_recv() {
+                Ok ( message ) => message,
+                Err( _ ) => return,
+            };
 
-        for &(sessionID, ref reason) in (*disconnectTCPConnectionsListGuard).iter(){
-            match (*tcpConnectionsGuard).get( Token(sessionID) ) {
-                Some( tcpConnectionMutex ) => {
-                    let tcpConnectionGuard=tcpConnectionMutex.lock().unwrap();
-                    (*tcpConnectionGuard)._disconnect(reason.clone());
+            match message {
+                TCPServerMessage::Send( sessionID, message ) => {
+                    match self.connections.get_mut( Token(sessionID) ) {
+                        Some( connection ) => connection.sendMessage(message),
+                        None => {},
+                    }
                 },
-                None => {},
+                TCPServerMessage::Disconnect( sessionID, reason ) => {
+                    match self.connections.get_mut( Token(sessionID) ) {
+                        Some( connection ) => {
+                            if connection.isActive {
+                                connection._disconnect(reason);
+                            }
+                        },
+                        None => {},
+                    }
+                },
+                TCPServerMessage::PlayerDataLoaded( sessionID, response ) => {
+                    match self.connections.get_mut( Token(sessionID) ) {
+                        Some( connection ) => {
+                            match connection.stage {
+                                TCPConnectionStage::LoadingPlayerDataFromMasterServer( _, _, _, _ ) => {
+                                    match connection.initializeUDPConnection(&response) {
+                                        Ok ( _ ) => {},
+                                        Err( e ) => connection.disconnect( DisconnectionReason::ServerError(DisconnectionCode::ServerError, e) ),
+                                    }
+                                },
+                                _ => {},
+                            }
+                        },
+                        None => {},
+                    }
+                },
+                TCPServerMessage::MasterServerUnavailable( sessionID ) => {
+                    match self.connections.get_mut( Token(sessionID) ) {
+                        Some( connection ) => {
+                            match connection.stage {
+                                TCPConnectionStage::LoadingPlayerDataFromMasterServer( _, _, _, _ ) =>
+                                    connection.offerLoginOrRegister(),
+                                _ => {},
+                            }
+                        },
+                        None => {},
+                    }
+                },
+                TCPServerMessage::MasterServerFailed( sessionID, masterServerIndex ) => {
+                    match self.connections.get_mut( Token(sessionID) ) {
+                        Some( connection ) => {
+                            let clientSessionID=match connection.stage {
+                                //ответ от сервера, который уже не ждем, игнорируем
+                                TCPConnectionStage::LoadingPlayerDataFromMasterServer( _, index, _, ref clientSessionID ) if index==masterServerIndex =>
+                                    Some( clientSessionID.clone() ),
+                                _ => None,
+                            };
+
+                            match clientSessionID {
+                                Some( clientSessionID ) => connection.loadPlayerData(clientSessionID, masterServerIndex+1),
+                                None => {},
+                            }
+                        },
+                        None => {},
+                    }
+                },
+                TCPServerMessage::UDPConnectionInitialized( sessionID, session ) => {
+                    match self.connections.get_mut( Token(sessionID) ) {
+                        Some( connection ) => {
+                            connection.hasUDPConnection=true;
+
+                            match connection.stage {
+                                TCPConnectionStage::UDPConnectionInitialization( _, _, _ ) => {
+                                    connection.stage=TCPConnectionStage::Playing;
+                                    connection.resumeToken=random::<u64>();
+                                    connection.sendMessage( ServerToClientTCPPacket::UDPConnectionInitialized(session, connection.resumeToken).pack() );
+                                },
+                                _ => {},
+                            }
+                        },
+                        None => {},
+                    }
+                },
+                TCPServerMessage::UDPConnectionRemoved( sessionID ) => {
+                    match self.connections.get_mut( Token(sessionID) ) {
+                        Some( connection ) => connection.hasUDPConnection=false,
+                        None => {},
+                    }
+                },
+                TCPServerMessage::ListConnections( reply ) => {
+                    let mut list=format!("{} connections", self.connections.len());
+
+                    for connection in self.connections.iter() {
+                        list.push_str( &format!("\n{} {} {}", usize::from(connection.token), connection.address, connection.stage.print()) );
+                    }
+
+                    reply.send( Ok(list) );
+                },
+                TCPServerMessage::SetAccepting( isAccepting ) => self.setAccepting(isAccepting),
             }
         }
-
-        (*disconnectTCPConnectionsListGuard).clear();
     }
 
     fn reregisterConnections(&mut self){
-        let connectionsGuard=self.server.tcpConnections.read().unwrap();
+        for connection in self.connections.iter_mut() {
+            match connection.reregister(&mut self.poll) {
+                Ok ( _ ) => {},
+                Err( e ) => connection.disconnect( DisconnectionReason::FatalError(e) ),
+            }
+        }
+    }
+
+    //Очередь упорядочена по времени постановки. Обычные игроки могут занять playersLimit-queue.reservedSlots мест, админы - все.
+    //Занятыми считаются места соединений, которые уже инициализируют UDP или играют, playersCount от Game может отставать
+    fn processQueue(&mut self) {
+        let playersLimit=*self.server.playersLimit.read().unwrap();
+        let serverConfig=&self.appData.serverConfig;
+
+        let mut usedSlots=0;
+        let mut queue=Vec::new();
+
+        for connection in self.connections.iter() {
+            if connection.shouldReset {
+                continue;
+            }
 
-        for connectionMutex in (*connectionsGuard).iter() {
-            let mut connection=connectionMutex.lock().unwrap();
-            (*connection).reregister(&mut self.poll).unwrap_or_else(|e| { (*connection).disconnect( DisconnectionReason::FatalError(e)); });
+            match connection.stage {
+                TCPConnectionStage::UDPConnectionInitialization( _, _, _ ) | TCPConnectionStage::Playing | TCPConnectionStage::Detached( _ ) =>
+                    usedSlots+=1,
+                TCPConnectionStage::Queued( ref ticket ) =>
+                    queue.push( (ticket.enqueueTime, usize::from(connection.token), ticket.isAdmin) ),
+                _ => {},
+            }
+        }
+
+        if queue.len()==0 {
+            return;
+        }
+
+        queue.sort();
+
+        let playersSlots=playersLimit.saturating_sub(serverConfig.queue_reservedSlots);
+        let mut waitingConnections=Vec::new();
+
+        for (_, sessionID, isAdmin) in queue {
+            let slots=if isAdmin { playersLimit } else { playersSlots };
+
+            if usedSlots<slots {
+                usedSlots+=1;
+                self.connections[Token(sessionID)].promoteFromQueue();
+            }else{
+                waitingConnections.push(sessionID);
+            }
+        }
+
+        let queueLength=if waitingConnections.len()>serverConfig.queue_length {
+            serverConfig.queue_length
+        }else{
+            waitingConnections.len()
+        };
+
+        for (index, sessionID) in waitingConnections.into_iter().enumerate() {
+            let connection=&mut self.connections[Token(sessionID)];
+
+            if index<queueLength {
+                connection.updateQueuePosition(index+1, queueLength, serverConfig.queue_positionUpdateInterval);
+            }else{
+                connection.disconnect( DisconnectionReason::ServerError( DisconnectionCode::ServerFull, String::from("Server and queue are full") ) );
+            }
         }
     }
 
@@ -105,6 +308,7 @@ impl TCPServer{
         if get_time().sec-self.tickTime>1 {
             self.tickTime=get_time().sec;
 
+            self.checkBans();
             self.checkConnections();
         }
     }
@@ -113,27 +317,94 @@ impl TCPServer{
         let mut removeConnections = Vec::new();
 
         //create list of connections we need to remove
-        {
-            let tcpConnectionsGuard=self.server.tcpConnections.read().unwrap();
+        for connection in self.connections.iter_mut() {
+            connection.check();
 
-            for connectionMutex in (*tcpConnectionsGuard).iter() {
-                let mut connection=connectionMutex.lock().unwrap();
-                let removeConnection=(*connection).check();
+            match connection.stage {
+                TCPConnectionStage::Detached( _ ) => connection.deregister(&mut self.poll),
+                _ => {},
+            }
 
-                if (*connection).shouldReset {
-                    removeConnections.push((*connection).token);
-                    (*connection).deregister(&mut self.poll);
+            if connection.shouldReset {
+                connection.deregister(&mut self.poll);
+
+                //пока UDPConnection с этим sessionID не удален, token не освобождаем
+                if !connection.hasUDPConnection {
+                    removeConnections.push(connection.token);
                 }
             }
         }
 
-        if removeConnections.len()>0 {
-            let mut tcpConnectionsGuard=self.server.tcpConnections.write().unwrap();
+        for token in removeConnections {
+            match self.connections.remove(token) {
+                Some( connection ) => self.releaseIP( connection.address.ip() ),
+                None => {},
+            }
+
+            match self.lanAuth {
+                Some( ref mut lanAuth ) => lanAuth.release( usize::from(token) ),
+                None => {},
+            }
+        }
+
+        //полные bucket-ы ничем не отличаются от новых
+        let mut fullAcceptLimiters=Vec::new();
 
-            for token in removeConnections {
-                (*tcpConnectionsGuard).remove(token);
+        for (ip, &mut (ref mut bucket, _)) in self.acceptLimiters.iter_mut() {
+            if bucket.isFull() {
+                fullAcceptLimiters.push(ip.clone());
             }
         }
+
+        for ip in fullAcceptLimiters {
+            self.acceptLimiters.remove(&ip);
+        }
+    }
+
+    fn releaseIP(&mut self, ip:IpAddr) {
+        let isLastConnection=match self.connectionsPerIP.get_mut(&ip) {
+            Some( connectionsNumber ) => {
+                *connectionsNumber-=1;
+                *connectionsNumber==0
+            },
+            None => false,
+        };
+
+        if isLastConnection {
+            self.connectionsPerIP.remove(&ip);
+        }
+    }
+
+    //Checks rate of accepts and number of connections of this IP. Rejection by rate is logged once, until bucket gets full again
+    fn acceptIP(&mut self, ip:IpAddr) -> bool {
+        {
+            let serverConfig=&self.appData.serverConfig;
+
+            let &mut (ref mut bucket, ref mut isReported)=self.acceptLimiters.entry(ip).or_insert_with(||
+                (TokenBucket::new(serverConfig.server_acceptsPerIPBurst, serverConfig.server_acceptsPerIPPerSecond), false)
+            );
+
+            if !bucket.take(1.0) {
+                if !*isReported {
+                    *isReported=true;
+                    self.appData.log.print( format!("[ERROR] Server: Too frequent connections from {}, they are rejected", ip) );
+                }
+
+                return false;
+            }
+        }
+
+        let connectionsNumber=self.connectionsPerIP.entry(ip).or_insert(0);
+
+        if *connectionsNumber>=self.appData.serverConfig.server_connectionsPerIPLimit {
+            self.appData.log.print( format!("[ERROR] Server: Connection from {} has been rejected : too many connections from this IP", ip) );
+
+            return false;
+        }
+
+        *connectionsNumber+=1;
+
+        true
     }
 
     fn processEvent(&mut self, token: Token, event: Ready) -> Result<(), &'static str>{
@@ -142,125 +413,209 @@ impl TCPServer{
                 return Err( "socket error" );
             }
 
-            if event.is_readable() {
+            if event.is_readable() && self.isAccepting {
                 self.processAccept();
             }
 
             Ok(())
+        }else if self.receiverToken == token {
+            Ok(()) //messages are processed after events
         }else{
-            if event.is_error() {
-                self.server.getTCPConnectionAnd(token, |connection| {
+            let readResult={
+                let connection=match self.connections.get_mut(token) {
+                    Some( connection ) => connection,
+                    None => return Ok(()),
+                };
+
+                if event.is_error() {
                     connection.disconnect( DisconnectionReason::FatalError("socket error") );
-                });
 
-                return Ok(())
-            }
+                    return Ok(())
+                }
 
-            if event.is_hup() {
-                self.server.getTCPConnectionAnd(token, |connection| {
+                if event.is_hup() {
                     connection.disconnect( DisconnectionReason::Hup );
-                });
 
-                return Ok(())
-            }
+                    return Ok(())
+                }
 
-            if event.is_writable() {
-                self.server.getTCPConnectionAnd(token, |connection| {
-                    if !connection.shouldReset {
-                        match connection.writeMessages(){
-                            Ok ( _ ) => connection.shouldReregister=true,
-                            Err( e ) => connection.disconnect( DisconnectionReason::FatalError(e) ),
-                        }
+                if event.is_writable() && !connection.shouldReset {
+                    match connection.writeMessages(){
+                        Ok ( _ ) => connection.shouldReregister=true,
+                        Err( e ) => connection.disconnect( DisconnectionReason::FatalError(e) ),
                     }
-                });
-            }
+                }
 
-            if event.is_readable() {
-                let readResult=self.server.getTCPConnectionAnd(token, | connection |{
-                    if connection.isActive {
-                        let readResult=connection.readMessage();
-
-                        match readResult{
-                            ReadResult::FatalError( e ) =>
-                                connection.disconnect( DisconnectionReason::FatalError(e) ),
-                            ReadResult::Error( e ) =>
-                                connection.disconnect( DisconnectionReason::ServerError( String::from(e) ) ),
-                            _=>
-                                connection.shouldReregister=true,
-                        }
+                if event.is_readable() && connection.isActive {
+                    let readResult=connection.readMessage();
 
-                        Some(readResult)
-                    }else{
-                        None
+                    match readResult{
+                        ReadResult::FatalError( e ) =>
+                            connection.disconnect( DisconnectionReason::FatalError(e) ),
+                        ReadResult::Error( e ) =>
+                            connection.disconnect( DisconnectionReason::ServerError( DisconnectionCode::ProtocolError, String::from(e) ) ),
+                        _=>
+                            connection.shouldReregister=true,
                     }
-                });
-
-                match readResult{
-                    Some(ReadResult::Ready( playerID, buffer )) => {
-                        let bufferGuard=buffer.lock().unwrap();
-
-                        match self.processMessage(token, playerID, &(*bufferGuard)) {
-                            Ok ( _ ) => {},
-                            Err( e ) => {
-                                self.server.getTCPConnectionAnd(token, |connection| {
-                                    connection.disconnect( DisconnectionReason::ServerError( e.clone() ) );
-                                });
-                            }
-                        };
-                    },
-                    _=>{},
+
+                    let rateLimitResult=match readResult {
+                        ReadResult::Ready( _, ref buffer ) => {
+                            let length=buffer.lock().unwrap().len();
+                            let rateLimit=getRateLimit(&self.appData.serverConfig, &connection.stage);
+
+                            connection.rateLimiter.take(rateLimit, length)
+                        },
+                        _ => Ok(()),
+                    };
+
+                    match rateLimitResult {
+                        Ok ( _ ) => {},
+                        Err( e ) => {
+                            self.appData.log.print( format!("[ERROR] TCP connection {} : {}", usize::from(token), e) );
+                            connection.disconnect( DisconnectionReason::ServerError( DisconnectionCode::RateLimitExceeded, String::from(e) ) );
+
+                            return Ok(());
+                        },
+                    }
+
+                    readResult
+                }else{
+                    ReadResult::NotReady
                 }
+            };
+
+            match readResult{
+                ReadResult::Ready( playerID, buffer ) => {
+                    let bufferGuard=buffer.lock().unwrap();
+
+                    match self.processMessage(token, playerID, &(*bufferGuard)) {
+                        Ok ( _ ) => {},
+                        Err( e ) => {
+                            match self.connections.get_mut(token) {
+                                Some( connection ) => connection.disconnect( DisconnectionReason::ServerError( DisconnectionCode::ProtocolError, e ) ),
+                                None => {},
+                            }
+                        }
+                    };
+                },
+                _=>{},
             }
 
             Ok(())
         }
     }
 
-    fn processMessage(&self, token:Token, playerID:Option<usize>, buffer:&Vec<u8>) -> Result<(), String> {
+    fn processMessage(&mut self, token:Token, playerID:Option<usize>, buffer:&Vec<u8>) -> Result<(), String> {
         let packet=try!(ClientToServerTCPPacket::unpack(buffer));
 
-        let process=match packet{
-            ClientToServerTCPPacket::ClientDesire( ref msg ) => {
-                self.server.getTCPConnectionAnd(token, |connection| {
-                    connection.disconnect( DisconnectionReason::ClientDesire(msg.clone()) );
-                });
+        match packet {
+            ClientToServerTCPPacket::Resume( sessionID, resumeToken ) => return self.resumeSession(token, sessionID, resumeToken),
+            _ => {},
+        }
+
+        let connection=match self.connections.get_mut(token) {
+            Some( connection ) => connection,
+            None => return Ok(()),
+        };
+
+        match packet{
+            ClientToServerTCPPacket::ClientDesire( msg ) => {
+                connection.disconnect( DisconnectionReason::ClientDesire(msg) );
+
+                Ok(())
+            },
+            ClientToServerTCPPacket::ClientError( msg ) => {
+                connection.disconnect( DisconnectionReason::ClientError(msg) );
 
-                false
+                Ok(())
             },
-            ClientToServerTCPPacket::ClientError( ref msg ) => {
-                self.server.getTCPConnectionAnd(token, |connection| {
-                    connection.disconnect( DisconnectionReason::ClientError(msg.clone()) );
-                });
+            ClientToServerTCPPacket::LANName( userName ) => {
+                match connection.stage {
+                    TCPConnectionStage::WaitingSessionID( _ ) => {},
+                    _ => return Err( String::from("unexpected ClientToServerTCPPacket::LANName") ),
+                }
+
+                let lanAuth=match self.lanAuth {
+                    Some( ref mut lanAuth ) => lanAuth,
+                    None => return Err( String::from("Server is not in LAN mode") ),
+                };
+
+                match lanAuth.acquireName(usize::from(token), &userName) {
+                    Ok ( userID ) => connection.allowUDPConnection(userID, userName),
+                    Err( e ) => connection.disconnect( DisconnectionReason::ServerError(DisconnectionCode::NameUnavailable, e) ),
+                }
 
-                false
+                Ok(())
             },
-            _=>
-                true,
+            ClientToServerTCPPacket::SessionID( _ ) if self.lanAuth.is_some() =>
+                Err( String::from("Server is in LAN mode, ClientToServerTCPPacket::LANName is expected") ),
+            packet => {
+                match playerID {
+                    Some(playerID) => {
+                        self.server.sendToGame( GameMessage::TCPPacket(playerID, packet) );
+
+                        Ok(())
+                    },
+                    None =>
+                        connection.processPacket(&packet),
+                }
+            },
+        }
+    }
+
+    //Old connection takes socket of the new one, so client gets its sessionID, UDP connection and player back
+    fn resumeSession(&mut self, token:Token, sessionID:usize, resumeToken:u64) -> Result<(), String> {
+        match self.connections.get(token) {
+            Some( connection ) => {
+                match connection.stage {
+                    TCPConnectionStage::WaitingSessionID( _ ) => {},
+                    _ => return Err( String::from("unexpected ClientToServerTCPPacket::Resume") ),
+                }
+            },
+            None => return Ok(()),
+        }
+
+        let isValidResume=match self.connections.get( Token(sessionID) ) {
+            Some( connection ) => {
+                match connection.stage {
+                    TCPConnectionStage::Detached( _ ) => connection.isActive && connection.resumeToken==resumeToken,
+                    _ => false,
+                }
+            },
+            None => false,
         };
 
-        if process{
-            match playerID {
-                Some(playerID) => {
-                    match self.server.getSafePlayerAnd(playerID, | player | { player.processMessage(&packet) }){
-                        Some( r ) => r,
-                        None => Ok(()),
-                    }
-                },
-                None => {
-                    self.server.getTCPConnectionAnd(token, |connection| {
-                        connection.processPacket(&packet)
-                    })
-                },
-            }
-        }else{
-            Ok(())
+        if !isValidResume || Token(sessionID)==token {
+            return Err( String::from("Session can not be resumed") );
+        }
+
+        let mut newConnection=self.connections.remove(token).unwrap();
+
+        let result=match self.connections.get_mut( Token(sessionID) ) {
+            Some( connection ) => connection.resume(&mut newConnection, &mut self.poll),
+            None => Ok(()),
+        };
+
+        //теперь у newConnection старый сокет и адрес, он закроется при drop
+        self.releaseIP( newConnection.address.ip() );
+
+        match result {
+            Ok ( _ ) => self.appData.log.print( format!("[INFO] Session {} has been resumed", sessionID) ),
+            Err( e ) => {
+                match self.connections.get_mut( Token(sessionID) ) {
+                    Some( connection ) => connection.disconnect( DisconnectionReason::FatalError(e) ),
+                    None => {},
+                }
+            },
         }
+
+        Ok(())
     }
 
     fn processAccept(&mut self) {
         loop {
-            let socket = match self.listener.accept() {
-                Ok((socket, _)) => socket,
+            let (socket, address) = match self.listener.accept() {
+                Ok((socket, address)) => (socket, address),
                 Err(e) => {
                     if e.kind() != ErrorKind::WouldBlock {
                         self.appData.log.print( String::from("[ERROR] Server: Accept tcp socket error") );
@@ -270,20 +625,24 @@ impl TCPServer{
                 }
             };
 
-            let mut connectionsGuard=self.server.tcpConnections.write().unwrap();
+            //socket закроется, когда его drop-нем
+            if !self.acceptIP( address.ip() ) {
+                continue;
+            }
 
-            let token=match (*connectionsGuard).vacant_entry() {
+            let token=match self.connections.vacant_entry() {
                 Some(entry) => {
-                    let connection = Mutex::new( TCPConnection::new(socket, entry.index(), self.server.clone()) );
+                    let connection = TCPConnection::new(socket, address, entry.index(), self.server.clone(), &self.appData.serverConfig.rateLimit_waitingSessionID);
                     entry.insert(connection).index()
                 },
                 None => {
+                    self.releaseIP( address.ip() );
                     self.appData.log.print( String::from("[ERROR] Server: Failed to insert tcp connection into slab(maybe connectionsLimit has been exceeded)") );
                     return;
                 }
             };
 
-            let registerFail=match (*connectionsGuard)[token].lock().unwrap().register(&mut self.poll) {
+            let registerFail=match self.connections[token].register(&mut self.poll) {
                 Ok(_) => false,
                 Err(e) => {
                     self.appData.log.print( format!("[ERROR] Server: Failed to register tcp conenction {:?} with poll : {:?}", token, e) );
@@ -292,41 +651,109 @@ impl TCPServer{
             };
 
             if registerFail {
-                (*connectionsGuard).remove(token);
+                self.connections.remove(token);
+                self.releaseIP( address.ip() );
+                continue;
+            }
+
+            //соединение уже создано, чтобы клиент узнал, что он забанен
+            match self.appData.getStorageAnd(|storage| storage.banList.checkIP( &address.ip() )) {
+                Some( ban ) => {
+                    self.appData.log.print( format!("[INFO] Connection from banned IP {} is rejected", address.ip()) );
+                    self.connections[token].disconnect( DisconnectionReason::ServerDesire(DisconnectionCode::Banned, ban.print()) );
+                },
+                None => {},
             }
         }
     }
 
+    //Bans can be changed by command or in file, so connected clients are checked again
+    fn checkBans(&mut self) {
+        match self.appData.getStorageAnd(|storage| storage.banList.reloadIfChanged()) {
+            Ok ( true ) => self.appData.log.print( String::from("[INFO] Ban list has been reloaded") ),
+            Ok ( false ) => {},
+            Err( e ) => self.appData.log.print( format!("[ERROR] Can not reload ban list : {}", e) ),
+        }
+
+        let bansVersion=self.appData.getStorageAnd(|storage| storage.banList.getVersion());
+
+        if bansVersion==self.bansVersion {
+            return;
+        }
+
+        self.bansVersion=bansVersion;
+
+        let appData=self.appData.clone();
+
+        appData.getStorageAnd(|storage| {
+            for connection in self.connections.iter_mut() {
+                if !connection.isActive {
+                    continue;
+                }
+
+                match storage.banList.checkIP( &connection.address.ip() ) {
+                    Some( ban ) => connection.disconnect( DisconnectionReason::ServerDesire(DisconnectionCode::Banned, ban.print()) ),
+                    None => {},
+                }
+            }
+        });
+    }
+
     fn register(&mut self) -> io::Result<()> {
-        self.poll.register(
+        try!(self.poll.register(
             &self.listener,
             self.token,
             Ready::readable() | Ready::error(),
             PollOpt::edge()
-        ).or_else(|e| {
-            Err(e)
-        })
+        ));
+
+        self.poll.register(
+            &self.receiver,
+            self.receiverToken,
+            Ready::readable(),
+            PollOpt::edge()
+        )
+    }
+
+    fn setAccepting(&mut self, isAccepting:bool) {
+        if isAccepting==self.isAccepting {
+            return;
+        }
+
+        self.isAccepting=isAccepting;
+
+        if isAccepting {
+            //клиенты, ждущие в backlog, вызовут событие сразу после регистрации
+            match self.poll.register(&self.listener, self.token, Ready::readable() | Ready::error(), PollOpt::edge()) {
+                Ok ( _ ) => self.appData.log.print( String::from("[INFO] TCP server accepts new connections") ),
+                Err( _ ) => self.appData.log.print( String::from("[ERROR] Can not register TCP listener") ),
+            }
+        }else{
+            self.poll.deregister(&self.listener);
+            self.appData.log.print( String::from("[INFO] TCP server does not accept new connections") );
+        }
     }
 
     fn sendAbschiedMessages(&mut self) -> Result<(), &'static str> {
         self.appData.log.print(format!("[INFO] Stoping TCP server"));
 
-        {
-            let mut tcpConnectionsGuard=self.server.tcpConnections.write().unwrap();
-
-            for connectionMutex in (*tcpConnectionsGuard).iter() {
-                let mut connection=connectionMutex.lock().unwrap();
+        self.setAccepting(false);
 
-                if connection.isActive {
-                    connection.disconnect( DisconnectionReason::ServerShutdown );
-                    connection.reregister(&mut self.poll);
-                }
+        for connection in self.connections.iter_mut() {
+            if connection.isActive {
+                connection._disconnect( DisconnectionReason::ServerShutdown );
+                connection.reregister(&mut self.poll);
             }
         }
 
         let waitTimeBegin=get_time();
 
-        while waitTimeBegin.sec + ACTIVITY_DISCONNECT_DELAY > get_time().sec {
+        while waitTimeBegin.sec + self.appData.serverConfig.shutdown_disconnectDelay > get_time().sec {
+            //все успели получить ServerShutdown - ждать больше нечего
+            if self.connections.iter().all(|connection| connection.shouldReset || !connection.hasMessagesToSend()) {
+                break;
+            }
+
             let eventsNumber=try!(self.poll.poll(&mut self.events, Some(Duration::new(0,100_000_000)) ).or(Err("Can not get eventsNumber") ) );
 
             for i in 0..eventsNumber {
@@ -335,8 +762,6 @@ impl TCPServer{
                 self.processEvent(event.token(), event.kind());
             }
 
-            self.disconnectTCPConnectionsFromList();
-
             self.reregisterConnections();
         }
 
@@ -351,29 +776,20 @@ impl TCPServer{
                 Err( e ) => self.appData.log.print( format!("[ERROR] Can not send abschiedMessages : {}", e) ),
             }
         }else{
-            let tcpConnectionsGuard=self.server.tcpConnections.read().unwrap();
-
-            for connectionMutex in (*tcpConnectionsGuard).iter() {
-                let mut connection=connectionMutex.lock().unwrap();
-
+            for connection in self.connections.iter_mut() {
                 if connection.isActive {
-                    connection.disconnect( DisconnectionReason::FatalError("server error") );
+                    connection._disconnect( DisconnectionReason::FatalError("server error") );
                 }
             }
         }
 
-        {
-            let mut tcpConnectionsGuard=self.server.tcpConnections.write().unwrap();
-
-            for connectionMutex in (*tcpConnectionsGuard).iter() {
-                let mut connection=connectionMutex.lock().unwrap();
-
-                (*connection).deregister(&mut self.poll);
-            }
-
-            (*tcpConnectionsGuard).clear();
+        for connection in self.connections.iter_mut() {
+            connection.deregister(&mut self.poll);
         }
 
+        self.connections.clear();
+        self.connectionsPerIP.clear();
+
         self.deregister();
     }
 
@@ -381,5 +797,25 @@ impl TCPServer{
         self.poll.deregister(
             &self.listener,
         );
+
+        self.poll.deregister(
+            &self.receiver,
+        );
+    }
+}
+
+fn getRateLimit<'a>(serverConfig:&'a ServerConfig, stage:&TCPConnectionStage) -> &'a RateLimit {
+    match *stage {
+        TCPConnectionStage::WaitingVersions( _ ) | TCPConnectionStage::WaitingSessionID( _ ) |
+        TCPConnectionStage::Disconnecting( _ ) | TCPConnectionStage::Detached( _ ) | TCPConnectionStage::Queued( _ ) =>
+            &serverConfig.rateLimit_waitingSessionID,
+        TCPConnectionStage::LoadingPlayerDataFromMasterServer( _, _, _, _ ) =>
+            &serverConfig.rateLimit_loadingPlayerData,
+        TCPConnectionStage::LoginOrRegister( _, _ ) =>
+            &serverConfig.rateLimit_loginOrRegister,
+        TCPConnectionStage::UDPConnectionInitialization( _, _, _ ) =>
+            &serverConfig.rateLimit_udpConnectionInitialization,
+        TCPConnectionStage::Playing =>
+            &serverConfig.rateLimit_playing,
     }
 }
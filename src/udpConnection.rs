@@ -1,24 +1,444 @@
 use std::net::SocketAddr;
-use server::DisconnectionReason;
+use std::collections::{VecDeque, HashMap};
+use std::io::ErrorKind;
+
+use byteorder::{ByteOrder, BigEndian};
+
+use mio::udp::UdpSocket;
+
+use server::{DisconnectionReason, getServerTime};
+use packet::{ServerToClientUDPPacket, UDP_PACKET_HEADER_LENGTH};
+use rateLimiter::{RateLimit, RateLimiter};
+
+/*
+Заголовок датаграммы (после session и time):
+[16..18] sequence - номер датаграммы, у каждой свой, в тч у повторно отправленных
+[18..20] ack - номер последней полученной датаграммы
+[20..24] ackBits - какие из 32 датаграмм до ack были получены
+[24]     flags
+[25]     channel - канал важной датаграммы
+[26..28] channelSequence - номер важной датаграммы внутри канала, по нему упорядочиваем и отбрасываем повторы
+
+Важные датаграммы отправляются повторно, пока не придет ack, причем каждый раз с новым sequence,
+поэтому получатель отбрасывает повторы по channelSequence, а не по sequence.
+Датаграмма, состоящая из одного заголовка, является просто ack.
+*/
+
+pub const UDP_CHANNELS_NUMBER: usize = 4;
+
+const FLAG_IMPORTANT: u8 = 1;
+const FLAG_HAS_ACK: u8 = 2;
+
+const RESEND_TIMEOUT: i64 = 200; //ms
+const RESEND_ATTEMPTS_LIMIT: usize = 25;
+const SENT_IMPORTANT_DATAGRAMS_LIMIT: usize = 256;
+const PENDING_DATAGRAMS_LIMIT: usize = 64;
+const SEND_QUEUE_LIMIT: usize = 512;
+
+#[derive(PartialEq, Eq, Copy, Clone)]
+pub enum UDPDelivery{
+    Unreliable,
+    Important( u8 ), //channel
+}
+
+struct SentDatagram{
+    sequences:Vec<u16>, //all copies have their own sequence, ack of any of them means, that datagram is delivered
+    datagram:Vec<u8>,
+    sendTime:i64,
+    attempts:usize,
+    isQueued:bool, //copy waits in sendQueue, so it is not resent again
+}
+
+struct IncomingChannel{
+    nextSequence:u16,
+    pending:HashMap<u16, Vec<u8>>,
+}
 
 pub struct UDPConnection{
     pub session:u64,
-    clientAddr:SocketAddr,
+    pub clientAddr:SocketAddr,
 
     pub shouldReset:bool,
+
+    pub lastReceiveTime:u64, //ms
+    pub lastPingTime:u64,
+    pub rateLimiter:RateLimiter,
+    pub isDetached:bool, //TCP connection is lost, so we wait for resume longer
+
+    localSequence:u16,
+    remoteSequence:u16,
+    receivedBits:u32,
+    hasReceived:bool,
+    pub shouldSendAck:bool,
+
+    outgoingChannelSequences:[u16; UDP_CHANNELS_NUMBER],
+    incomingChannels:Vec<IncomingChannel>,
+    sentImportantDatagrams:VecDeque<SentDatagram>,
+
+    sendQueue:VecDeque<(Vec<u8>, bool)>, //datagram, is important
 }
 
 impl UDPConnection {
-    pub fn new(session:u64, clientAddr:SocketAddr) -> UDPConnection{
+    pub fn new(session:u64, clientAddr:SocketAddr, rateLimit:&RateLimit) -> UDPConnection{
+        let mut incomingChannels=Vec::with_capacity(UDP_CHANNELS_NUMBER);
+
+        for _ in 0..UDP_CHANNELS_NUMBER {
+            incomingChannels.push( IncomingChannel{ nextSequence:0, pending:HashMap::new() } );
+        }
+
         UDPConnection{
             session:session,
             clientAddr:clientAddr,
 
             shouldReset:false,
+
+            lastReceiveTime:getServerTime(),
+            lastPingTime:0,
+            rateLimiter:RateLimiter::new(rateLimit),
+            isDetached:false,
+
+            localSequence:0,
+            remoteSequence:0,
+            receivedBits:0,
+            hasReceived:false,
+            shouldSendAck:false,
+
+            outgoingChannelSequences:[0; UDP_CHANNELS_NUMBER],
+            incomingChannels:incomingChannels,
+            sentImportantDatagrams:VecDeque::with_capacity(32),
+
+            sendQueue:VecDeque::with_capacity(32),
         }
     }
 
     pub fn _disconnect(&mut self, reason:DisconnectionReason){
         self.shouldReset=true;
     }
+
+    pub fn sendDatagram(&mut self, datagram:Vec<u8>, delivery:UDPDelivery) -> Result<(), &'static str> {
+        let isImportant=match delivery {
+            UDPDelivery::Important( _ ) => true,
+            UDPDelivery::Unreliable => false,
+        };
+
+        if self.sendQueue.len()>=SEND_QUEUE_LIMIT {
+            if !isImportant {
+                return Ok(()); //unreliable datagram may be lost
+            }
+
+            return Err("UDP send queue is overflowed");
+        }
+
+        let datagram=try!(self.packDatagram(datagram, delivery));
+
+        self.sendQueue.push_back( (datagram, isImportant) );
+
+        Ok(())
+    }
+
+    ///Sends queued datagrams, resends undelivered important ones and acks. Returns false, if socket would block
+    pub fn flushDatagrams(&mut self, socket:&UdpSocket) -> Result<bool, &'static str> {
+        for datagram in try!(self.takeDatagramsToResend()) {
+            self.sendQueue.push_back( (datagram, true) );
+        }
+
+        if self.shouldSendAck && self.sendQueue.len()==0 {
+            let mut ackDatagram=ServerToClientUDPPacket::packAck(self.session);
+            let sequence=self.nextLocalSequence();
+
+            self.writeHeader(&mut ackDatagram, sequence, 0, 0, 0);
+            self.sendQueue.push_back( (ackDatagram, false) );
+        }
+
+        self.shouldSendAck=false;
+
+        loop{
+            let (mut datagram, isImportant)=match self.sendQueue.pop_front() {
+                Some( d ) => d,
+                None => return Ok(true),
+            };
+
+            //датаграмма могла долго ждать в очереди, ack должен быть свежим
+            self.writeAckFields(&mut datagram);
+
+            let wouldBlock=match socket.send_to(&datagram[..], &self.clientAddr) {
+                Ok ( Some( _ ) ) => false,
+                Ok ( None ) => true,
+                Err( e ) => {
+                    if e.kind()==ErrorKind::WouldBlock {
+                        true
+                    }else{
+                        return Err("UDP Socket write error");
+                    }
+                },
+            };
+
+            if wouldBlock {
+                if isImportant {
+                    self.sendQueue.push_front( (datagram, isImportant) );
+                }
+
+                return Ok(false);
+            }
+
+            //таймаут повторной отправки отсчитываем от настоящей отправки, а не от попадания в очередь
+            if isImportant {
+                let sequence=BigEndian::read_u16(&datagram[16..18]);
+
+                for sentDatagram in self.sentImportantDatagrams.iter_mut() {
+                    if sentDatagram.sequences.last()==Some(&sequence) {
+                        sentDatagram.isQueued=false;
+                        sentDatagram.sendTime=getServerTime() as i64;
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    ///Fills the reliability part of header of datagram, packed by ServerToClientUDPPacket, and remembers it, if it is important
+    pub fn packDatagram(&mut self, mut datagram:Vec<u8>, delivery:UDPDelivery) -> Result<Vec<u8>, &'static str> {
+        let sequence=self.nextLocalSequence();
+
+        match delivery {
+            UDPDelivery::Unreliable => {
+                self.writeHeader(&mut datagram, sequence, 0, 0, 0);
+            },
+            UDPDelivery::Important( channel ) => {
+                if channel as usize>=UDP_CHANNELS_NUMBER {
+                    return Err("Invalid UDP channel");
+                }
+
+                if self.sentImportantDatagrams.len()>=SENT_IMPORTANT_DATAGRAMS_LIMIT {
+                    return Err("Too many undelivered important datagrams");
+                }
+
+                let channelSequence=self.outgoingChannelSequences[channel as usize];
+                self.outgoingChannelSequences[channel as usize]=channelSequence.wrapping_add(1);
+
+                self.writeHeader(&mut datagram, sequence, FLAG_IMPORTANT, channel, channelSequence);
+
+                self.sentImportantDatagrams.push_back(
+                    SentDatagram{
+                        sequences:vec![sequence],
+                        datagram:datagram.clone(),
+                        sendTime:getServerTime() as i64,
+                        attempts:1,
+                        isQueued:true,
+                    }
+                );
+            },
+        }
+
+        self.shouldSendAck=false;
+
+        Ok(datagram)
+    }
+
+    ///Processes acks of datagram and returns datagrams, which are ready to be processed, in order of their channel
+    pub fn receiveDatagram(&mut self, datagram:&[u8]) -> Result<Vec<Vec<u8>>, &'static str> {
+        if datagram.len()<UDP_PACKET_HEADER_LENGTH {
+            return Err("Too short datagram");
+        }
+
+        self.lastReceiveTime=getServerTime();
+
+        let sequence=BigEndian::read_u16(&datagram[16..18]);
+        let ack=BigEndian::read_u16(&datagram[18..20]);
+        let ackBits=BigEndian::read_u32(&datagram[20..24]);
+        let flags=datagram[24];
+        let channel=datagram[25] as usize;
+        let channelSequence=BigEndian::read_u16(&datagram[26..28]);
+
+        if flags & FLAG_HAS_ACK!=0 {
+            self.processAck(ack, ackBits);
+        }
+
+        let isDuplicate=self.registerRemoteSequence(sequence);
+
+        if datagram.len()==UDP_PACKET_HEADER_LENGTH { //only ack
+            return Ok(Vec::new());
+        }
+
+        if flags & FLAG_IMPORTANT==0 {
+            if isDuplicate {
+                return Ok(Vec::new());
+            }
+
+            return Ok(vec![datagram.to_vec()]);
+        }
+
+        //important datagram, we must ack it even if it is duplicate, because our previous ack may be lost
+        self.shouldSendAck=true;
+
+        if channel>=UDP_CHANNELS_NUMBER {
+            return Err("Invalid UDP channel");
+        }
+
+        let incomingChannel=&mut self.incomingChannels[channel];
+
+        if channelSequence==incomingChannel.nextSequence {
+            let mut datagrams=vec![datagram.to_vec()];
+            incomingChannel.nextSequence=incomingChannel.nextSequence.wrapping_add(1);
+
+            loop{
+                let nextSequence=incomingChannel.nextSequence;
+
+                match incomingChannel.pending.remove(&nextSequence) {
+                    Some( pendingDatagram ) => {
+                        datagrams.push(pendingDatagram);
+                        incomingChannel.nextSequence=nextSequence.wrapping_add(1);
+                    },
+                    None => break,
+                }
+            }
+
+            Ok(datagrams)
+        }else if isMoreRecent(channelSequence, incomingChannel.nextSequence) {
+            if !incomingChannel.pending.contains_key(&channelSequence) {
+                if incomingChannel.pending.len()>=PENDING_DATAGRAMS_LIMIT {
+                    return Err("Too many pending important datagrams");
+                }
+
+                incomingChannel.pending.insert(channelSequence, datagram.to_vec());
+            }
+
+            Ok(Vec::new())
+        }else{ //already processed
+            Ok(Vec::new())
+        }
+    }
+
+    ///Returns important datagrams, which have not been acked in time. Each of them gets new sequence and fresh ack
+    pub fn takeDatagramsToResend(&mut self) -> Result<Vec<Vec<u8>>, &'static str> {
+        let time=getServerTime() as i64;
+        let mut datagrams=Vec::new();
+
+        for i in 0..self.sentImportantDatagrams.len() {
+            if self.sentImportantDatagrams[i].isQueued || time-self.sentImportantDatagrams[i].sendTime<RESEND_TIMEOUT {
+                continue;
+            }
+
+            if self.sentImportantDatagrams[i].attempts>=RESEND_ATTEMPTS_LIMIT {
+                return Err("Important datagram has not been delivered");
+            }
+
+            let sequence=self.nextLocalSequence();
+            let (ack, ackBits, flags)=self.ackFields();
+
+            let sentDatagram=&mut self.sentImportantDatagrams[i];
+
+            sentDatagram.sequences.push(sequence);
+            sentDatagram.sendTime=time;
+            sentDatagram.attempts+=1;
+            sentDatagram.isQueued=true;
+
+            BigEndian::write_u16(&mut sentDatagram.datagram[16..18], sequence);
+            BigEndian::write_u16(&mut sentDatagram.datagram[18..20], ack);
+            BigEndian::write_u32(&mut sentDatagram.datagram[20..24], ackBits);
+            sentDatagram.datagram[24]=flags | FLAG_IMPORTANT;
+
+            datagrams.push(sentDatagram.datagram.clone());
+        }
+
+        if datagrams.len()>0 {
+            self.shouldSendAck=false;
+        }
+
+        Ok(datagrams)
+    }
+
+    fn nextLocalSequence(&mut self) -> u16 {
+        let sequence=self.localSequence;
+        self.localSequence=self.localSequence.wrapping_add(1);
+        sequence
+    }
+
+    fn ackFields(&self) -> (u16, u32, u8) {
+        if self.hasReceived {
+            (self.remoteSequence, self.receivedBits, FLAG_HAS_ACK)
+        }else{
+            (0, 0, 0)
+        }
+    }
+
+    fn writeHeader(&self, datagram:&mut Vec<u8>, sequence:u16, flags:u8, channel:u8, channelSequence:u16) {
+        BigEndian::write_u16(&mut datagram[16..18], sequence);
+        datagram[24]=flags;
+        datagram[25]=channel;
+        BigEndian::write_u16(&mut datagram[26..28], channelSequence);
+
+        self.writeAckFields(datagram);
+    }
+
+    fn writeAckFields(&self, datagram:&mut Vec<u8>) {
+        let (ack, ackBits, ackFlags)=self.ackFields();
+
+        BigEndian::write_u16(&mut datagram[18..20], ack);
+        BigEndian::write_u32(&mut datagram[20..24], ackBits);
+        datagram[24]=(datagram[24] & !FLAG_HAS_ACK) | ackFlags;
+    }
+
+    fn processAck(&mut self, ack:u16, ackBits:u32) {
+        self.sentImportantDatagrams.retain(|sentDatagram| {
+            //ack мог прийти на любую из отправленных копий
+            !sentDatagram.sequences.iter().any(|&sequence| {
+                let distance=ack.wrapping_sub(sequence) as u32;
+
+                if distance==0 {
+                    true
+                }else if distance<=32 {
+                    ackBits & (1<<(distance-1))!=0
+                }else{
+                    false
+                }
+            })
+        });
+    }
+
+    //returns true, if datagram with this sequence has been received before(or it is too old to know it)
+    fn registerRemoteSequence(&mut self, sequence:u16) -> bool {
+        if !self.hasReceived {
+            self.hasReceived=true;
+            self.remoteSequence=sequence;
+            self.receivedBits=0;
+
+            return false;
+        }
+
+        if isMoreRecent(sequence, self.remoteSequence) {
+            let shift=sequence.wrapping_sub(self.remoteSequence) as u32;
+
+            self.receivedBits=if shift<32 {
+                (self.receivedBits<<shift) | (1<<(shift-1))
+            }else if shift==32 {
+                1<<31
+            }else{
+                0
+            };
+
+            self.remoteSequence=sequence;
+
+            false
+        }else{
+            let distance=self.remoteSequence.wrapping_sub(sequence) as u32;
+
+            if distance==0 || distance>32 {
+                return true;
+            }
+
+            let bit=1<<(distance-1);
+
+            if self.receivedBits & bit!=0 {
+                true
+            }else{
+                self.receivedBits|=bit;
+                false
+            }
+        }
+    }
+}
+
+fn isMoreRecent(a:u16, b:u16) -> bool {
+    (a>b && a-b<=32768) || (a<b && b-a>32768)
 }
@@ -1,32 +1,44 @@
 use std::thread;
-use std::thread::JoinHandle;
-use std::sync::{Mutex,Arc,RwLock,Weak};
+use std::sync::Arc;
 
-use std::io::{self, ErrorKind};
-use std::rc::Rc;
+use std::io::ErrorKind;
 
 use mio::*;
 use mio::udp::*;
-use slab::Slab;
-use std::net::SocketAddr;
+use mio::channel::Receiver;
+use std::net::{SocketAddr, IpAddr};
+
+use std::collections::HashMap;
 
 use time::{get_time};
 use std::time::Duration;
 
 use appData::AppData;
-use server::{Server, DisconnectionReason, DisconnectionSource};
+use server::{Server, DisconnectionReason, DisconnectionSource, getServerTime};
 use server::ServerState;
 
-use tcpConnection::{TCPConnection, ReadResult, TCPConnectionStage};
-use udpConnection::UDPConnection;
-use player::Player;
+use tcpServer::TCPServerMessage;
+use udpConnection::{UDPConnection, UDPDelivery};
+use rateLimiter::{IPRateLimiters, takeIPRateLimit, removeFullRateLimiters};
+use game::GameMessage;
+use version::{Version, GAME_VERSION};
+use modLoader::readActiveMods;
+use lanDiscovery::LANDiscovery;
 
-use packet::{ServerToClientUDPPacket, ClientToServerUDPPacket};
+use packet::{ServerToClientUDPPacket, ClientToServerUDPPacket, DisconnectionCode, UDP_PACKET_HEADER_LENGTH};
+use packet::{ServerInfo, SERVER_QUERY_MAGIC, SERVER_QUERY_REQUEST_LENGTH};
 
 use rand::random;
 
 pub const UDP_DATAGRAM_LENGTH_LIMIT:usize = 4*1024;
 
+pub enum UDPServerMessage{
+    Send( usize, ServerToClientUDPPacket, UDPDelivery ),
+    Disconnect( usize, DisconnectionReason ),
+    AllowConnection( usize, usize, String ), //sessionID, userID, userName - TCP connection waits for ClientToServerUDPPacket::Initialization
+    SetDetached( usize, bool ), //TCP connection of session has been lost or resumed
+}
+
 pub struct UDPSocket{
     pub socket: UdpSocket, //listening socket
     pub token: Token,
@@ -37,30 +49,92 @@ pub struct UDPServer{
     pub server:Arc<Server>,
     pub poll: Poll,
     pub events: Events, // a list of events to process
+    socket:UDPSocket,
     readBuffer:Vec<u8>,
 
-    pub sessions:Vec<u64>,
+    connections:Vec<Option<UDPConnection>>, //index is sessionID
+    allowedConnections:HashMap<usize, (usize, String)>,
+    handshakeRateLimiters:IPRateLimiters,
+    queryRateLimiters:IPRateLimiters,
+    activeMods:Vec<String>, //for answers to server queries
+    lanDiscovery:Option<LANDiscovery>,
+    receiver:Receiver<UDPServerMessage>,
+    receiverToken:Token,
+
     tickTime:i64,
+    socketIsWritable:bool,
+    sendStartIndex:usize, //connections are flushed in turn, so the ones at the end of the list do not starve
 }
 
 impl UDPServer{
+    pub fn new(appData:Arc<AppData>, server:Arc<Server>, addr:&SocketAddr, receiver:Receiver<UDPServerMessage>) -> Result<UDPServer, String> {
+        let connectionsLimit=appData.serverConfig.server_connectionsLimit;
+
+        let mut connections=Vec::with_capacity(connectionsLimit);
+
+        for _ in 0..connectionsLimit {
+            connections.push(None);
+        }
+
+        //без списка модов сервер все равно может работать, просто не сообщит их в ответ на запрос
+        let activeMods=match readActiveMods() {
+            Ok ( activeMods ) => activeMods,
+            Err( e ) => {
+                appData.log.print( format!("[WARNING] UDP server : {}", e) );
+                Vec::new()
+            },
+        };
+
+        //без обнаружения в LAN к серверу все равно можно подключиться по адресу
+        let lanDiscovery=match LANDiscovery::new(&appData) {
+            Ok ( lanDiscovery ) => lanDiscovery,
+            Err( e ) => {
+                appData.log.print( format!("[ERROR] {}", e) );
+                None
+            },
+        };
+
+        Ok(UDPServer{
+            poll:try!(Poll::new().or( Err(format!("Can not create UDP event poll")) )),
+            events:Events::with_capacity(appData.serverConfig.server_playersLimit*8),
+            socket:try!( UDPSocket::new(addr.clone()) ),
+            readBuffer:vec![0; UDP_DATAGRAM_LENGTH_LIMIT],
+
+            connections:connections,
+            allowedConnections:HashMap::new(),
+            handshakeRateLimiters:HashMap::new(),
+            queryRateLimiters:HashMap::new(),
+            activeMods:activeMods,
+            lanDiscovery:lanDiscovery,
+            receiver:receiver,
+            receiverToken:Token(20_000_001),
+
+            tickTime:get_time().sec,
+            socketIsWritable:true,
+            sendStartIndex:0,
+
+            appData:appData,
+            server:server,
+        })
+    }
+
     pub fn process(&mut self) -> Result<(), &'static str>{
-        try!(self.server.udpSocket.lock().unwrap().register(&mut self.poll).or(Err( "Can not register server poll" ) ) );
+        try!(self.socket.register(&mut self.poll));
 
-        {
-            let mut serverStateGuard=self.server.state.write().unwrap();
+        try!(self.poll.register(
+            &self.receiver,
+            self.receiverToken,
+            Ready::readable(),
+            PollOpt::edge()
+        ).or(Err( "Can not register UDP server channel" ) ) );
 
-            if *serverStateGuard==ServerState::Initialization(0) {
-                (*serverStateGuard)=ServerState::Initialization(1);
-            }else if *serverStateGuard==ServerState::Initialization(1) {
-                *serverStateGuard=ServerState::Processing;
-            }else if *serverStateGuard!=ServerState::Processing {//Disconnect кем-то другим
-                return Ok(());
-            }
+        match self.lanDiscovery {
+            Some( ref mut lanDiscovery ) => try!(lanDiscovery.register(&mut self.poll)),
+            None => {},
         }
 
-        while match *self.server.state.read().unwrap() { ServerState::Initialization(_) => true, _=>false} {
-            thread::sleep_ms(10);
+        if !self.server.onThreadInitialized() {
+            return Ok(());
         }
 
         self.appData.log.print(format!("[INFO] UDP server is ready"));
@@ -74,9 +148,9 @@ impl UDPServer{
                 try!(self.processEvent(event.token(), event.kind()));
             }
 
-            self.disconnectUDPConnectionsFromList();
+            self.processServerMessages();
 
-            //try!(self.server.udpSocket.lock().unwrap().reregister(&mut self.poll));
+            self.sendDatagrams();
 
             self.processTick();
         }
@@ -84,21 +158,106 @@ impl UDPServer{
         Ok(())
     }
 
-    fn disconnectUDPConnectionsFromList(&mut self){
-        let disconnectUDPConnectionsListGuard=self.server.disconnectUDPConnectionsList.lock().unwrap();
-        let udpConnectionsGuard=self.server.udpConnections.read().unwrap();
+    fn processServerMessages(&mut self){
+        loop{
+            let message=match self.receiver.try_recv() {
+                Ok ( message ) => message,
+                Err( _ ) => return,
+            };
 
-        for &(sessionID, ref reason) in (*disconnectUDPConnectionsListGuard).iter(){
-            match (*udpConnectionsGuard).get( sessionID ) {
-                Some( udpConnectionMutex ) => {
-                    let udpConnectionGuard=udpConnectionMutex.lock().unwrap();
-                    (*udpConnectionGuard)._disconnect(reason.clone());
+            match message {
+                UDPServerMessage::Send( sessionID, packet, delivery ) => {
+                    let result=match self.connections[sessionID] {
+                        Some( ref mut connection ) => {
+                            if !connection.shouldReset {
+                                match packet.pack(connection.session) {
+                                    Ok ( datagram ) => connection.sendDatagram(datagram, delivery).or_else(|e| Err(String::from(e))),
+                                    Err( e ) => Err(e),
+                                }
+                            }else{
+                                Ok(())
+                            }
+                        },
+                        None => Ok(()),
+                    };
+
+                    match result {
+                        Ok ( _ ) => {},
+                        Err( e ) => self.disconnectConnection( sessionID, DisconnectionReason::ServerError(DisconnectionCode::ServerError, e) ),
+                    }
+                },
+                UDPServerMessage::Disconnect( sessionID, reason ) => {
+                    self.allowedConnections.remove(&sessionID);
+
+                    match self.connections[sessionID] {
+                        Some( ref mut connection ) => connection._disconnect(reason),
+                        None => {},
+                    }
+                },
+                UDPServerMessage::AllowConnection( sessionID, userID, userName ) => {
+                    self.allowedConnections.insert(sessionID, (userID, userName));
+                },
+                UDPServerMessage::SetDetached( sessionID, isDetached ) => {
+                    match self.connections[sessionID] {
+                        Some( ref mut connection ) => connection.isDetached=isDetached,
+                        None => {},
+                    }
+                },
+            }
+        }
+    }
+
+    //disconnects UDP connection and tells about it other parts of session
+    fn disconnectConnection(&mut self, sessionID:usize, reason:DisconnectionReason){
+        match self.connections[sessionID] {
+            Some( ref mut connection ) => {
+                if connection.shouldReset {
+                    return;
+                }
+
+                connection._disconnect(reason.clone());
+            },
+            None => return,
+        }
+
+        self.server.disconnect( sessionID, reason, DisconnectionSource::UDP );
+    }
+
+    fn sendDatagrams(&mut self) {
+        if !self.socketIsWritable {//wait for writable event
+            return;
+        }
+
+        let mut failedConnections=Vec::new();
+        let connectionsCount=self.connections.len();
+
+        for i in 0..connectionsCount {
+            let sessionID=(self.sendStartIndex+i)%connectionsCount;
+
+            match self.connections[sessionID] {
+                Some( ref mut connection ) => {
+                    if connection.shouldReset {
+                        continue;
+                    }
+
+                    match connection.flushDatagrams(&self.socket.socket) {
+                        Ok ( true ) => {},
+                        Ok ( false ) => {
+                            //следующий раз начнем со следующего соединения, а это дождется своей очереди
+                            self.sendStartIndex=(sessionID+1)%connectionsCount;
+                            self.socketIsWritable=false;
+                            break;
+                        },
+                        Err( e ) => failedConnections.push( (sessionID, e) ),
+                    }
                 },
                 None => {},
             }
         }
 
-        (*disconnectUDPConnectionsListGuard).clear();
+        for (sessionID, e) in failedConnections {
+            self.disconnectConnection( sessionID, DisconnectionReason::ServerError( DisconnectionCode::ServerError, String::from(e) ) );
+        }
     }
 
     fn processTick(&mut self) {
@@ -107,76 +266,223 @@ impl UDPServer{
 
             self.checkConnections();
         }
+
+        match self.lanDiscovery {
+            Some( ref mut lanDiscovery ) => lanDiscovery.processTick(&self.appData, &self.server),
+            None => {},
+        }
     }
 
     fn checkConnections(&mut self){
-        let mut removeConnections = Vec::new();
+        let time=getServerTime();
+        let pingInterval=self.appData.serverConfig.server_udpPingInterval*1000;
+        let timeout=self.appData.serverConfig.server_udpTimeout*1000;
+        let resumeGraceTime=self.appData.serverConfig.server_resumeGraceTime*1000;
 
-        //create list of connections we need to remove
-        {
-            let udpConnectionsGuard=self.server.udpConnections.read().unwrap();
+        let mut timedOutConnections=Vec::new();
+        let mut failedConnections=Vec::new();
 
-            for connectionMutex in (*udpConnectionsGuard).iter() {
-                let mut connection=connectionMutex.lock().unwrap();
+        for (sessionID, connection) in self.connections.iter_mut().enumerate() {
+            let removeConnection=match *connection {
+                Some( ref mut connection ) => {
+                    if !connection.shouldReset {
+                        let silenceTime=time.saturating_sub(connection.lastReceiveTime);
 
-                if (*connection).shouldReset {
-                    removeConnections.push(( (*connection).session & 0x0000_0000_0000_FFFF) as usize );
-                }
+                        let timeout=if connection.isDetached && resumeGraceTime>timeout { resumeGraceTime } else { timeout };
+
+                        if silenceTime>=timeout {
+                            timedOutConnections.push(sessionID);
+                        }else if silenceTime>=pingInterval && time.saturating_sub(connection.lastPingTime)>=pingInterval {
+                            connection.lastPingTime=time;
+
+                            let result=match ServerToClientUDPPacket::Ping.pack(connection.session) {
+                                Ok ( datagram ) => connection.sendDatagram(datagram, UDPDelivery::Unreliable).or_else(|e| Err(String::from(e))),
+                                Err( e ) => Err(e),
+                            };
+
+                            match result {
+                                Ok ( _ ) => {},
+                                Err( e ) => failedConnections.push( (sessionID, e) ),
+                            }
+                        }
+                    }
+
+                    connection.shouldReset
+                },
+                None => false,
+            };
+
+            if removeConnection {
+                *connection=None;
+
+                //TCPConnection ждет этого, чтобы его sessionID не занял новый клиент, пока здесь остается старое соединение
+                self.server.sendToTCPServer( TCPServerMessage::UDPConnectionRemoved(sessionID) );
             }
         }
 
-        if removeConnections.len()>0 {
-            let mut udpConnectionsGuard=self.server.udpConnections.write().unwrap();
+        for sessionID in timedOutConnections {
+            self.disconnectConnection( sessionID, DisconnectionReason::ServerError( DisconnectionCode::Timeout, String::from("UDP connection timeout") ) );
+        }
 
-            for sessionID in removeConnections {
-                self.sessions[sessionID]=0;
-                (*udpConnectionsGuard).remove(sessionID);
-            }
+        for (sessionID, e) in failedConnections {
+            self.disconnectConnection( sessionID, DisconnectionReason::ServerError(DisconnectionCode::ServerError, e) );
         }
+
+        removeFullRateLimiters(&mut self.handshakeRateLimiters);
+        removeFullRateLimiters(&mut self.queryRateLimiters);
     }
 
     fn processEvent(&mut self, token: Token, event: Ready) -> Result<(), &'static str> {
-        println!("event!!");
-        if event.is_readable() {
-            let result=self.server.udpSocket.lock().unwrap().socket.recv_from( &mut self.readBuffer[..] );
+        if token==self.receiverToken {
+            return Ok(()); //messages are processed after events
+        }
 
-            match result{
-                Ok (None) => {}
-                Ok (Some(( length, clientAddr ))) => {
-                    if length>=16 && length<UDP_DATAGRAM_LENGTH_LIMIT {
-                        let session=ClientToServerUDPPacket::unpackSession(&self.readBuffer);
+        match self.lanDiscovery {
+            Some( ref mut lanDiscovery ) if lanDiscovery.token==token => return lanDiscovery.processEvent(&self.appData, &self.server),
+            _ => {},
+        }
 
-                        if session==0 {
-                            match self.processAccept( clientAddr ){
-                                Ok ( _ ) => {},
-                                Err( e ) => self.appData.log.print( format!("[ERROR] UDP Connection acception error : {}", e) ),
-                            }
-                        }else{
-                            let playerID=(session & 0x0000_0000_0000_FFFF) as usize;
+        if event.is_writable() {
+            self.socketIsWritable=true;
+        }
 
-                            if playerID<self.sessions.len() && session==self.sessions[playerID] {
-                                let time=ClientToServerUDPPacket::unpackTime(&self.readBuffer);
+        if event.is_readable() {
+            loop{ //edge - we should read all datagrams
+                let result=self.socket.socket.recv_from( &mut self.readBuffer[..] );
+
+                match result{
+                    Ok (None) => break,
+                    Ok (Some(( length, clientAddr ))) => {
+                        let receiveTime=getServerTime();
+
+                        if length>=UDP_PACKET_HEADER_LENGTH && length<UDP_DATAGRAM_LENGTH_LIMIT {
+                            let session=ClientToServerUDPPacket::unpackSession(&self.readBuffer);
+
+                            if session==SERVER_QUERY_MAGIC {
+                                self.processQuery( clientAddr, length );
+                            }else if session==0 {
+                                if !self.takeHandshakeRateLimit( clientAddr.ip(), length ) {
+                                    continue;
+                                }
 
-                                match ClientToServerUDPPacket::unpack(&self.readBuffer) {
-                                    Ok ( ref packet ) => {
-                                        self.server.getSafePlayerAnd(playerID, |player| player.processDatagram(packet, time));
-                                    },
-                                    Err( e ) => self.appData.log.print( format!("[ERROR] Player {} : {}", playerID, e) ),
+                                match self.processAccept( clientAddr ){
+                                    Ok ( _ ) => {},
+                                    Err( e ) => self.appData.log.print( format!("[ERROR] UDP Connection acception error : {}", e) ),
+                                }
+                            }else{
+                                let playerID=(session & 0x0000_0000_0000_FFFF) as usize;
+
+                                let isValidSession=match self.connections.get(playerID) {
+                                    Some( &Some( ref connection ) ) => connection.session==session && !connection.shouldReset,
+                                    _ => false,
+                                };
+
+                                if isValidSession {
+                                    self.processDatagram(playerID, clientAddr, length, receiveTime);
                                 }
                             }
                         }
-                    }
-                },
-                Err( e ) => return Err( "UDP Socket read error" ),
+                    },
+                    Err( e ) => {
+                        if e.kind()==ErrorKind::WouldBlock {
+                            break;
+                        }
+
+                        return Err( "UDP Socket read error" );
+                    },
+                }
             }
         }
 
         Ok(())
     }
 
+    fn processDatagram(&mut self, playerID:usize, clientAddr:SocketAddr, length:usize, receiveTime:u64) {
+        let receiveResult=match self.connections[playerID] {
+            Some( ref mut connection ) => {
+                let receiveResult=match connection.rateLimiter.take(&self.appData.serverConfig.rateLimit_udp, length) {
+                    Ok ( _ ) => connection.receiveDatagram(&self.readBuffer[..length])
+                        .or_else(|e| Err(DisconnectionReason::ClientError( String::from(e) ))),
+                    Err( e ) => {
+                        self.appData.log.print( format!("[ERROR] UDP connection {} : {}", playerID, e) );
+                        Err( DisconnectionReason::ServerError( DisconnectionCode::RateLimitExceeded, String::from(e) ) )
+                    },
+                };
+
+                //клиент переподключился(resume) или NAT выдал ему новый порт - session знает только он, отвечаем туда, откуда она пришла
+                if receiveResult.is_ok() && connection.clientAddr!=clientAddr {
+                    self.appData.log.print( format!("[INFO] UDP connection {} has moved from {} to {}", playerID, connection.clientAddr, clientAddr) );
+                    connection.clientAddr=clientAddr;
+                }
+
+                receiveResult
+            },
+            None => return,
+        };
+
+        let datagrams=match receiveResult {
+            Ok( datagrams ) => datagrams,
+            Err( reason ) => {
+                self.disconnectConnection( playerID, reason );
+                return;
+            },
+        };
+
+        for datagram in datagrams.iter() {
+            let time=ClientToServerUDPPacket::unpackTime(datagram);
+
+            match ClientToServerUDPPacket::unpack(datagram) {
+                Ok ( ClientToServerUDPPacket::Pong ) => {}, //lastReceiveTime is already updated
+                Ok ( packet ) => self.server.sendToGame( GameMessage::UDPPacket(playerID, packet, time, receiveTime) ),
+                Err( e ) => self.appData.log.print( format!("[ERROR] Player {} : {}", playerID, e) ),
+            }
+        }
+    }
+
+    //Returns false, if this IP sends too many Initialization datagrams
+    fn takeHandshakeRateLimit(&mut self, ip:IpAddr, length:usize) -> bool {
+        takeIPRateLimit(&self.appData.log, &mut self.handshakeRateLimiters, &self.appData.serverConfig.rateLimit_udpHandshake, ip, length, "UDP handshakes")
+    }
+
+    //Запрос не создает сессию и отвечается сразу. Ответ не длиннее SERVER_QUERY_REQUEST_LENGTH*4, а частота ответов одному IP ограничена,
+    //поэтому подделав адрес отправителя, сервер нельзя использовать для усиления трафика
+    fn processQuery(&mut self, clientAddr:SocketAddr, length:usize) {
+        if length<SERVER_QUERY_REQUEST_LENGTH {
+            return;
+        }
+
+        if !takeIPRateLimit(&self.appData.log, &mut self.queryRateLimiters, &self.appData.serverConfig.rateLimit_udpQuery, clientAddr.ip(), length, "UDP queries") {
+            return;
+        }
+
+        let clientTime=ClientToServerUDPPacket::unpackTime(&self.readBuffer);
+
+        match self.getServerInfo().pack(SERVER_QUERY_MAGIC, clientTime) {
+            Ok ( datagram ) => {self.socket.socket.send_to(&datagram[..], &clientAddr);}, //если сокет занят, клиент повторит запрос
+            Err( e ) => self.appData.log.print( format!("[ERROR] UDP query answer : {}", e) ),
+        }
+    }
+
+    fn getServerInfo(&self) -> ServerInfo {
+        let serverConfig=&self.appData.serverConfig;
+
+        let mut serverInfo=ServerInfo{
+            name:serverConfig.server_name.clone(),
+            map:serverConfig.getMapDescription(),
+            mode:serverConfig.server_mode.clone(),
+            playersCount:*self.server.playersCount.read().unwrap(),
+            playersLimit:*self.server.playersLimit.read().unwrap(),
+            gameVersion:Version::fromBytes(GAME_VERSION),
+            mods:self.activeMods.clone(),
+        };
+
+        serverInfo.fitIntoAnswer();
+
+        serverInfo
+    }
+
     fn processAccept(&mut self, clientAddr:SocketAddr) -> Result<(), &'static str> {
-        //в редкой сетуации, когда TCPConnection есть(прошло более 1 сек), а UDP и Player нет, лучше отказать, пусть попросит еще раз
-        //locks tcp/udp/players at the same time - may cause deadlock, особенно со стороны disconnect, от которого и все лочится
+        //TCPServer заранее сообщает, какую сессию ждать(AllowConnection), поэтому чужие данные не блокируем
         let packet=try!( ClientToServerUDPPacket::unpack(&self.readBuffer) );
 
         let sessionID=match packet{
@@ -184,84 +490,105 @@ impl UDPServer{
             _=>return Err("Expected only ClientToServerUDPPacket::Initialization packet"),
         };
 
-        if sessionID<self.sessions.len(){
+        if sessionID>=self.connections.len(){
             return Err("too much sessionID");
         }
 
-        if self.sessions[sessionID]!=0 {//self.sessions и self.server.udpConnections чистятся одним потоком в одной функции, поэтому существование UDPConnection гарантировано
-            let udpConnectionsGuard=self.server.udpConnections.read().unwrap();
-            let connection=(*udpConnectionsGuard)[sessionID].lock().unwrap();
+        let isRepeatedInitialization=match self.connections[sessionID] {
+            Some( ref connection ) => {
+                if connection.shouldReset {
+                    return Err("Inactive UDP Connection still exists");
+                }
 
-            if !connection.shouldReset{
-                //send package
-                return Ok(())
-            }else{
-                return Err("Inactive UDP Connection still exists");
-            }
-        }
+                //ответ мог потеряться, и клиент повторил Initialization, но session сообщаем только тому же адресу
+                if connection.clientAddr!=clientAddr {
+                    return Err("Repeated initialization from another address");
+                }
 
-        {
+                true
+            },
+            None => false,
+        };
 
-            //GUARD лочим сперва TCP Connections, чтобы всякие trydisconnect если и вызвались, то записались бы в очереди
-            let tcpConnectionsGuard=self.server.tcpConnections.read().unwrap();
+        if isRepeatedInitialization {
+            return self.sendInitializationAccepted(sessionID);
+        }
 
-            match (*tcpConnectionsGuard).get( Token(sessionID) ) {
-                Some( tcpConnectionMutex ) => {
-                    let connection=tcpConnectionMutex.lock().unwrap();
+        let (userID, userName)=match self.allowedConnections.remove(&sessionID) {
+            Some( userData ) => userData,
+            None => return Err("no active TCP Connection"),
+        };
 
-                    if !(*connection).isActive {
-                        return Err("no active TCP Connection");
-                    }
-                },
-                None => return Err("no active TCP Connection"),
-            }
+        let randomBytes = (random::<u64>()%0xFFFF_FFFF_FFFE+1)<<16; //>0
 
-            let tcpConnectionGuard=(*tcpConnectionsGuard)[ Token(sessionID) ].lock().unwrap();
+        let session=randomBytes+sessionID as u64;
 
-            let (userID, userName) = match (*tcpConnectionGuard).stage{
-                TCPConnectionStage::UDPConnectionInitialization ( _, ref userID, ref userName) =>
-                    (*userID, userName.clone()),
-                _=>
-                    return Err("stage is no UDP Initialization"),
-            };
+        self.connections[sessionID]=Some( UDPConnection::new(session, clientAddr, &self.appData.serverConfig.rateLimit_udp) );
 
-            {
-                //GUARD
-                let playersGuard=self.server.players.read().unwrap();
+        self.server.sendToTCPServer( TCPServerMessage::UDPConnectionInitialized(sessionID, session) );
+        self.server.sendToGame( GameMessage::AddPlayer(sessionID, userID, userName) );
 
-                if (*playersGuard).contains(sessionID){
-                    return Err("Inactive Player still exists");
-                }
-            }
+        self.sendInitializationAccepted(sessionID)
+    }
 
-            (*tcpConnectionGuard).stage=TCPConnectionStage::Playing;
+    //Unreliable, because client repeats Initialization until it gets the answer
+    fn sendInitializationAccepted(&mut self, sessionID:usize) -> Result<(), &'static str> {
+        let connection=match self.connections[sessionID] {
+            Some( ref mut connection ) => connection,
+            None => return Err("no UDP Connection"),
+        };
 
-            let randomBytes = (rand::random::<u64>()%0xFFFF_FFFF_FFFE+1)<<16; //>0
+        let datagram=try!(
+            ServerToClientUDPPacket::InitializationAccepted( connection.session ).pack( connection.session )
+            .or( Err("Can not pack InitializationAccepted packet") )
+        );
 
-            let session=randomBytes+sessionID as u64;
+        connection.sendDatagram(datagram, UDPDelivery::Unreliable)
+    }
 
-            self.sessions[sessionID]=session;
+    //Farewell is sent directly, without queue and resending, it may be lost - client also gets it by TCP
+    fn sendAbschiedDatagrams(&mut self) {
+        self.appData.log.print(format!("[INFO] Stoping UDP server"));
 
-            {
-                let udpConnectionsGuard=self.server.udpConnections.write().unwrap();
+        for connection in self.connections.iter_mut() {
+            match *connection {
+                Some( ref mut connection ) => {
+                    if connection.shouldReset {
+                        continue;
+                    }
 
-                let udpConnection=UDPConnection::new(session, clientAddr);
-                (*udpConnectionsGuard).insert_at(sessionID,Mutex::new(udpConnection));
-            }
+                    let datagram=match ServerToClientUDPPacket::Disconnected( DisconnectionCode::ServerShutdown, String::new() ).pack(connection.session) {
+                        Ok ( datagram ) => datagram,
+                        Err( _ ) => continue,
+                    };
+
+                    match connection.packDatagram(datagram, UDPDelivery::Unreliable) {
+                        Ok ( datagram ) => {self.socket.socket.send_to(&datagram[..], &connection.clientAddr);},
+                        Err( _ ) => {},
+                    }
 
-            {
-                let playersGuard=self.server.players.write().unwrap();
-                let player=Player::new(self.server.clone(), sessionID, userID, userName);
-                (*playersGuard).insert_at(sessionID,RwLock::new(player));
+                    connection._disconnect( DisconnectionReason::ServerShutdown );
+                },
+                None => {},
             }
         }
-
-        //send package
-        return Ok(())
     }
 
     pub fn onServerShutdown(&mut self, sendAbschiedMessage:bool) -> Result<(),String> {
-        self.server.udpSocket.lock().unwrap().deregister(&mut self.poll);
+        if sendAbschiedMessage {
+            self.sendAbschiedDatagrams();
+        }
+
+        self.socket.deregister(&mut self.poll);
+
+        match self.lanDiscovery {
+            Some( ref mut lanDiscovery ) => lanDiscovery.deregister(&mut self.poll),
+            None => {},
+        }
+
+        self.poll.deregister(
+            &self.receiver
+        );
 
         Ok(())
     }
@@ -286,35 +613,8 @@ impl UDPSocket{
         )
     }
 
-    /*
-    //вообще на стороне клиента должно приниматься сообщение целиком
-    pub fn send(&mut self, msg:&Vec<u8>, important:bool) -> Result<bool,String> {
-        let sent=match self.socket.send_to(&msg[..], &self.serverAddr){
-            Ok ( s ) => {
-                match s {
-                    None => false,
-                    Some( n ) => n==msg.len(),
-                }
-            },
-            Err( e ) => return Err(format!("{:?}",e)),
-        };
-
-        if sent {
-            return Ok(true);
-        }
-
-        println!("not sent");
-
-        if important {
-            self.importantMessages.push_front(msg.clone());
-        }
-
-        Ok(false)
-    }
-    */
-
     pub fn register(&mut self, poll:&mut Poll) -> Result<(), &'static str> {
-        let interest=Ready::readable();//Ready::readable();
+        let interest=Ready::readable() | Ready::writable();
 
         poll.register(
             &self.socket,
@@ -1,6 +1,13 @@
 use std::error::Error;
 use std::cmp::Ordering;
 
+use rustc_serialize::{Encodable, Encoder, Decodable, Decoder};
+
+///Version of layout of packets, client and server must have the same one.
+///Increase it on each change of any packet, except Versions, IncompatibleVersions and Disconnected, which never move
+pub const PROTOCOL_VERSION: [u8;4] = [0,3,0,0];
+pub const GAME_VERSION: [u8;4] = [0,1,0,0];
+
 #[derive(Copy, Clone, Eq)]
 pub struct Version {
     versionBytes:[u8;4],
@@ -8,6 +15,16 @@ pub struct Version {
 }
 
 impl Version {
+    pub fn fromBytes( v:[u8;4] ) -> Version {
+        Version{
+            versionBytes:v,
+            versionHash:(v[0] as u32) * 16777216+
+                        (v[1] as u32) * 65536+
+                        (v[2] as u32) * 256+
+                        (v[3] as u32),
+        }
+    }
+
     pub fn parse( string:&String ) -> Result< Version, String >{
         let mut v=[0u32;4];
         let mut c=0;
@@ -74,3 +91,22 @@ impl PartialEq for Version{
     fn eq(&self, other: &Self) -> bool { self.versionHash==other.versionHash }
     fn ne(&self, other: &Self) -> bool { self.versionHash!=other.versionHash }
 }
+
+impl Encodable for Version {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        self.versionHash.encode(s)
+    }
+}
+
+impl Decodable for Version {
+    fn decode<D: Decoder>(d: &mut D) -> Result<Version, D::Error> {
+        let versionHash=try!(u32::decode(d));
+
+        Ok(Version::fromBytes([
+            (versionHash >> 24) as u8,
+            (versionHash >> 16) as u8,
+            (versionHash >> 8) as u8,
+            versionHash as u8,
+        ]))
+    }
+}
@@ -0,0 +1,120 @@
+use std::fs::{self, File};
+use std::error::Error;
+use std::io::{Read, Write, ErrorKind};
+
+use std::sync::Mutex;
+use std::collections::HashMap;
+
+use time::get_time;
+
+use description;
+
+/*
+Состояние мира хранится в world.save в формате description:
+map = "generate map"
+savedAt = "1700000000"
+players = [ { userID = "1073741825", userName = "Griefer", lastSeen = "1700000000" } ]
+Game сохраняет его при остановке сервера, до того, как Storage будет разрушен.
+Игроки, которых нет на сервере, остаются в файле с прежним lastSeen.
+Пишем сначала во временный файл, чтобы при сбое не потерять прошлое сохранение.
+*/
+
+const WORLD_FILE_NAME: &'static str = "world.save";
+const WORLD_TEMPORARY_FILE_NAME: &'static str = "world.save.tmp";
+
+#[derive(Clone)]
+pub struct PlayerRecord{
+    pub userID:usize,
+    pub userName:String,
+    pub lastSeen:i64, //sec
+}
+
+struct World{
+    map:String,
+    players:HashMap<usize, PlayerRecord>, //userID
+}
+
+pub struct WorldStore{
+    world:Mutex<World>,
+}
+
+impl WorldStore{
+    pub fn load() -> Result<WorldStore, String> {
+        let mut file=match File::open(WORLD_FILE_NAME) {
+            Ok( f ) => f,
+            Err( ref e ) if e.kind()==ErrorKind::NotFound => {
+                return Ok( WorldStore{ world:Mutex::new( World{ map:String::new(), players:HashMap::new() } ) } );
+            },
+            Err( e ) => return Err(format!("Can not read file \"{}\" : {}", WORLD_FILE_NAME, e.description())),
+        };
+
+        let mut content = String::new();
+        match file.read_to_string(&mut content){
+            Ok( _ )  => {},
+            Err( e ) => return Err(format!("Can not read file \"{}\" : {}", WORLD_FILE_NAME, e.description())),
+        }
+
+        let world=try!(description::parse( &content, |root| {
+            let mut players=HashMap::new();
+
+            for playerValue in try!(root.getList("players")).iter() {
+                let playerMap=try!(playerValue.getMap());
+                let userID=try!(playerMap.getStringAs::<usize>("userID"));
+
+                players.insert(userID,
+                    PlayerRecord{
+                        userID:userID,
+                        userName:try!(playerMap.getString("userName")).clone(),
+                        lastSeen:try!(playerMap.getStringAs::<i64>("lastSeen")),
+                    }
+                );
+            }
+
+            Ok(
+                World{
+                    map:try!(root.getString("map")).clone(),
+                    players:players,
+                }
+            )
+        }).or_else(|e| Err(format!("File \"{}\" : {}", WORLD_FILE_NAME, e))));
+
+        Ok( WorldStore{ world:Mutex::new(world) } )
+    }
+
+    ///Remembers players, who are on server now, and writes all into file
+    pub fn save(&self, map:&str, onlinePlayers:Vec<PlayerRecord>) -> Result<usize, String> {
+        let mut world=self.world.lock().unwrap();
+
+        world.map=String::from(map);
+
+        for player in onlinePlayers {
+            world.players.insert(player.userID, player);
+        }
+
+        //в description нет экранирования, поэтому кавычки убираем
+        let mut content=format!("map = \"{}\"\nsavedAt = \"{}\"\nplayers = [", world.map.replace('"', "'"), get_time().sec);
+
+        for (i, player) in world.players.values().enumerate() {
+            if i>0 {
+                content.push_str("\n");
+            }
+
+            content.push_str( &format!(" {{ userID = \"{}\", userName = \"{}\", lastSeen = \"{}\" }}", player.userID, player.userName.replace('"', "'"), player.lastSeen) );
+        }
+
+        content.push_str(" ]\n");
+
+        {
+            let mut file=try!(File::create(WORLD_TEMPORARY_FILE_NAME)
+                .or_else(|e| Err(format!("Can not write file \"{}\" : {}", WORLD_TEMPORARY_FILE_NAME, e.description()))));
+
+            try!(file.write_all(content.as_bytes()).and_then(|_| file.sync_all())
+                .or_else(|e| Err(format!("Can not write file \"{}\" : {}", WORLD_TEMPORARY_FILE_NAME, e.description()))));
+        }
+
+        try!(fs::rename(WORLD_TEMPORARY_FILE_NAME, WORLD_FILE_NAME)
+            .or_else(|e| Err(format!("Can not write file \"{}\" : {}", WORLD_FILE_NAME, e.description()))));
+
+        Ok(world.players.len())
+    }
+}